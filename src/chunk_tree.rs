@@ -1,56 +1,301 @@
-#[derive(Default, Clone, Copy)]
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+use crate::structs::*;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ChunkTreeKey {
     pub start: u64,
     pub size: u64,
 }
 
-#[derive(Default, Clone, Copy)]
-pub struct ChunkTreeValue {
+impl ChunkTreeKey {
+    /// First logical address after the chunk
+    fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// Where one stripe of a chunk lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkStripe {
+    pub devid: u64,
+    /// Physical offset of the stripe on `devid`
     pub offset: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkTreeValue {
+    /// Block group type and profile bits, `BTRFS_BLOCK_GROUP_*`
+    pub ty: u64,
+    pub stripe_len: u64,
+    pub sub_stripes: u16,
+    pub stripes: Vec<ChunkStripe>,
+}
+
+impl ChunkTreeValue {
+    /// Build from a chunk item and all of its stripes, rejecting layouts the profile can't have
+    pub fn new(chunk: &BtrfsChunk, stripes: &[BtrfsStripe]) -> Result<Self> {
+        let profile = chunk.ty & BTRFS_BLOCK_GROUP_PROFILE_MASK;
+        if profile.count_ones() > 1 {
+            bail!("chunk has multiple profiles type={:#x}", chunk.ty);
+        }
+        if stripes.is_empty() || stripes.len() != chunk.num_stripes as usize {
+            bail!(
+                "chunk has num_stripes={} but {} stripes",
+                chunk.num_stripes,
+                stripes.len()
+            );
+        }
+        if chunk.stripe_len == 0 {
+            bail!("chunk has stripe_len=0");
+        }
+
+        let n = stripes.len();
+        let min_stripes = match profile {
+            BTRFS_BLOCK_GROUP_RAID1 | BTRFS_BLOCK_GROUP_DUP | BTRFS_BLOCK_GROUP_RAID5 => 2,
+            BTRFS_BLOCK_GROUP_RAID6 | BTRFS_BLOCK_GROUP_RAID1C3 => 3,
+            BTRFS_BLOCK_GROUP_RAID1C4 => 4,
+            _ => 1,
+        };
+        if n < min_stripes {
+            bail!(
+                "chunk type={:#x} needs at least {} stripes but has {}",
+                chunk.ty,
+                min_stripes,
+                n
+            );
+        }
+        if profile == BTRFS_BLOCK_GROUP_RAID10
+            && (chunk.sub_stripes == 0 || !n.is_multiple_of(chunk.sub_stripes as usize))
+        {
+            bail!(
+                "RAID10 chunk has sub_stripes={} with num_stripes={}",
+                chunk.sub_stripes,
+                n
+            );
+        }
+
+        Ok(Self {
+            ty: chunk.ty,
+            stripe_len: chunk.stripe_len,
+            sub_stripes: chunk.sub_stripes,
+            stripes: stripes
+                .iter()
+                .map(|s| ChunkStripe {
+                    devid: s.devid,
+                    offset: s.offset,
+                })
+                .collect(),
+        })
+    }
+
+    /// A SINGLE profile chunk on one device
+    pub fn single(devid: u64, offset: u64) -> Self {
+        Self {
+            ty: 0,
+            stripe_len: 64 << 10,
+            sub_stripes: 0,
+            stripes: vec![ChunkStripe { devid, offset }],
+        }
+    }
+
+    pub fn profile(&self) -> u64 {
+        self.ty & BTRFS_BLOCK_GROUP_PROFILE_MASK
+    }
+
+    /// Number of parity stripes in every full stripe
+    fn parity_stripes(&self) -> usize {
+        match self.profile() {
+            BTRFS_BLOCK_GROUP_RAID5 => 1,
+            BTRFS_BLOCK_GROUP_RAID6 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Map `offset` bytes into the chunk of `size` bytes
+    fn map(&self, offset: u64, size: u64) -> StripeMapping {
+        let n = self.stripes.len();
+        let stripe_nr = offset / self.stripe_len;
+        let stripe_offset = offset % self.stripe_len;
+        let stripe_remaining = self.stripe_len - stripe_offset;
+        let at = |idx: usize, row: u64| {
+            let stripe = self.stripes[idx];
+            (
+                stripe.devid,
+                stripe.offset + row * self.stripe_len + stripe_offset,
+            )
+        };
+
+        match self.profile() {
+            BTRFS_BLOCK_GROUP_RAID0 => StripeMapping {
+                mirrors: vec![at((stripe_nr % n as u64) as usize, stripe_nr / n as u64)],
+                len: stripe_remaining,
+                parity: None,
+            },
+            BTRFS_BLOCK_GROUP_RAID10 => {
+                let sub_stripes = self.sub_stripes as usize;
+                let factor = (n / sub_stripes) as u64;
+                let idx = (stripe_nr % factor) as usize * sub_stripes;
+                let row = stripe_nr / factor;
+                StripeMapping {
+                    mirrors: (idx..idx + sub_stripes).map(|i| at(i, row)).collect(),
+                    len: stripe_remaining,
+                    parity: None,
+                }
+            }
+            BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6 => {
+                // Parity rotates by one device every full stripe
+                let data_stripes = n - self.parity_stripes();
+                let row = stripe_nr / data_stripes as u64;
+                let data_index = (stripe_nr % data_stripes as u64) as usize;
+                let rotated = |i: usize| at((row as usize + i) % n, row);
+                let data: Vec<_> = (0..data_stripes).map(rotated).collect();
+                StripeMapping {
+                    mirrors: vec![data[data_index]],
+                    len: stripe_remaining,
+                    parity: Some(ParityMapping {
+                        data_index,
+                        p: rotated(data_stripes),
+                        q: (self.parity_stripes() == 2).then(|| rotated(data_stripes + 1)),
+                        data,
+                    }),
+                }
+            }
+            // SINGLE, DUP and the RAID1 variants keep a full copy on every stripe
+            _ => StripeMapping {
+                mirrors: self
+                    .stripes
+                    .iter()
+                    .map(|s| (s.devid, s.offset + offset))
+                    .collect(),
+                len: size - offset,
+                parity: None,
+            },
+        }
+    }
+}
+
+/// Where a logical address lives on disk
+#[derive(Debug, PartialEq, Eq)]
+pub struct StripeMapping {
+    /// (devid, physical) of every copy of the data. Any of them can be read.
+    pub mirrors: Vec<(u64, u64)>,
+    /// Bytes from the logical address until the mapping changes
+    pub len: u64,
+    /// Set for RAID5/6, to rebuild the data when no copy can be read
+    pub parity: Option<ParityMapping>,
+}
+
+impl StripeMapping {
+    /// Number of ways the data can be read. For RAID5/6 the second way is rebuilding it from
+    /// parity.
+    pub fn num_copies(&self) -> usize {
+        self.mirrors.len() + self.parity.is_some() as usize
+    }
+}
+
+/// (devid, physical) of the other stripes in a RAID5/6 full stripe, at the same offset
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParityMapping {
+    /// Which of `data` holds the logical address
+    pub data_index: usize,
+    pub data: Vec<(u64, u64)>,
+    pub p: (u64, u64),
+    /// Only for RAID6
+    pub q: Option<(u64, u64)>,
+}
+
+/// Chunks by logical start address. Chunks never overlap, so the only chunk that can hold an
+/// address is the last one starting at or before it.
 #[derive(Default)]
 pub struct ChunkTreeCache {
-    inner: Vec<(ChunkTreeKey, ChunkTreeValue)>,
+    inner: BTreeMap<u64, (ChunkTreeKey, ChunkTreeValue)>,
 }
 
 impl ChunkTreeCache {
-    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) {
-        if self.contains_overlapping(&key) {
-            panic!("overlapping chunk range detected");
+    /// Add a chunk. Adding the same chunk again is a no-op, since the chunks in the superblock's
+    /// sys_array are also in the chunk tree. Fails if the chunk overlaps a different one.
+    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) -> Result<()> {
+        if key.size == 0 || key.start.checked_add(key.size).is_none() {
+            bail!(
+                "chunk at logical={} has invalid size={}",
+                key.start,
+                key.size
+            );
         }
 
-        self.inner.push((key, value));
-    }
-
-    pub fn mapping_kv(&self, logical: u64) -> Option<(ChunkTreeKey, ChunkTreeValue)> {
-        for (k, v) in &self.inner {
-            if logical >= k.start && logical < (k.start + k.size) {
-                return Some((*k, *v));
+        if let Some((k, v)) = self.inner.get(&key.start) {
+            if *k == key && *v == value {
+                return Ok(());
+            }
+        }
+        let prev = self.inner.range(..=key.start).next_back();
+        let next = self.inner.range(key.start..).next();
+        for (k, _) in prev.into_iter().chain(next).map(|(_, kv)| kv) {
+            if k.start < key.end() && key.start < k.end() {
+                bail!(
+                    "chunk at logical={} size={} overlaps chunk at logical={} size={}",
+                    key.start,
+                    key.size,
+                    k.start,
+                    k.size
+                );
             }
         }
 
-        None
+        self.inner.insert(key.start, (key, value));
+
+        Ok(())
     }
 
-    pub fn offset(&self, logical: u64) -> Option<u64> {
-        if let Some((k, v)) = self.mapping_kv(logical) {
-            Some(v.offset + (logical - k.start))
+    pub fn mapping_kv(&self, logical: u64) -> Option<(&ChunkTreeKey, &ChunkTreeValue)> {
+        let (_, (k, v)) = self.inner.range(..=logical).next_back()?;
+        if logical < k.end() {
+            Some((k, v))
         } else {
             None
         }
     }
 
-    fn contains_overlapping(&self, key: &ChunkTreeKey) -> bool {
-        for (k, _) in &self.inner {
-            if (key.start > k.start && key.start < (k.start + k.size))
-                || ((key.start + key.size) > k.start && (key.start + key.size) < (k.start + k.size))
-            {
-                return true;
-            }
-        }
+    /// First chunk that starts after `logical`
+    pub fn next_chunk(&self, logical: u64) -> Option<(&ChunkTreeKey, &ChunkTreeValue)> {
+        let start = logical.checked_add(1)?;
+        self.inner.range(start..).next().map(|(_, (k, v))| (k, v))
+    }
+
+    /// Last chunk that ends at or before `logical`
+    pub fn prev_chunk(&self, logical: u64) -> Option<(&ChunkTreeKey, &ChunkTreeValue)> {
+        self.inner
+            .range(..logical)
+            .rev()
+            .map(|(_, (k, v))| (k, v))
+            .find(|(k, _)| k.end() <= logical)
+    }
+
+    /// Every chunk, in logical address order
+    pub fn iter(&self) -> impl Iterator<Item = (&ChunkTreeKey, &ChunkTreeValue)> {
+        self.inner.values().map(|(k, v)| (k, v))
+    }
+
+    pub fn offset(&self, logical: u64) -> Option<u64> {
+        self.physical(logical).map(|(_, offset)| offset)
+    }
+
+    /// Returns the devid and physical offset of the first copy of `logical`
+    pub fn physical(&self, logical: u64) -> Option<(u64, u64)> {
+        self.map(logical).map(|m| m.mirrors[0])
+    }
 
-        false
+    /// Returns every location `logical` can be read from
+    pub fn map(&self, logical: u64) -> Option<StripeMapping> {
+        let (k, v) = self.mapping_kv(logical)?;
+        let mut mapping = v.map(logical - k.start, k.size);
+        // Striped profiles can't run past the end of the chunk either
+        mapping.len = mapping.len.min(k.end() - logical);
+
+        Some(mapping)
     }
 }
 
@@ -59,18 +304,21 @@ fn test_ctc_basic() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 5, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeValue::single(1, 234),
+    )
+    .unwrap();
 
     assert_eq!(tree.offset(0), Some(123));
     assert_eq!(tree.offset(1), Some(124));
     assert_eq!(tree.offset(5), Some(234));
     assert_eq!(tree.offset(6), Some(235));
     assert_eq!(tree.offset(11), None);
+    assert_eq!(tree.physical(6), Some((1, 235)));
 }
 
 #[test]
@@ -78,24 +326,29 @@ fn test_ctc_random_order() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 10, size: 3 },
-        ChunkTreeValue { offset: 345 },
-    );
+        ChunkTreeValue::single(1, 345),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 25, size: 5 },
-        ChunkTreeValue { offset: 456 },
-    );
+        ChunkTreeValue::single(1, 456),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 15, size: 5 },
-        ChunkTreeValue { offset: 567 },
-    );
+        ChunkTreeValue::single(1, 567),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 5, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeValue::single(1, 234),
+    )
+    .unwrap();
 
     assert_eq!(tree.offset(0), Some(123));
     assert_eq!(tree.offset(1), Some(124));
@@ -109,35 +362,175 @@ fn test_ctc_random_order() {
 }
 
 #[test]
-#[should_panic]
 fn test_ctc_edge_overlap() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 4, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
-
-    // unreached
-    assert!(false);
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 4, size: 5 },
+            ChunkTreeValue::single(1, 234),
+        )
+        .is_err());
 }
 
 #[test]
-#[should_panic]
 fn test_ctc_inside_overlap() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 1, size: 2 },
+            ChunkTreeValue::single(1, 234),
+        )
+        .is_err());
+}
+
+#[test]
+fn test_ctc_containing_overlap() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(
+        ChunkTreeKey { start: 10, size: 5 },
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 5, size: 20 },
+            ChunkTreeValue::single(1, 234),
+        )
+        .is_err());
+    // Same start, different size
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 10, size: 6 },
+            ChunkTreeValue::single(1, 123),
+        )
+        .is_err());
+
+    // The same chunk again is fine, but not with different stripes
     tree.insert(
-        ChunkTreeKey { start: 1, size: 2 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeKey { start: 10, size: 5 },
+        ChunkTreeValue::single(1, 123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 10, size: 5 },
+            ChunkTreeValue::single(2, 123),
+        )
+        .is_err());
+    assert_eq!(tree.iter().count(), 1);
+}
+
+#[test]
+fn test_ctc_adjacent() {
+    let mut tree = ChunkTreeCache::default();
+    for start in [20, 0, 10] {
+        tree.insert(
+            ChunkTreeKey { start, size: 5 },
+            ChunkTreeValue::single(1, start),
+        )
+        .unwrap();
+    }
+
+    let starts: Vec<_> = tree.iter().map(|(k, _)| k.start).collect();
+    assert_eq!(starts, [0, 10, 20]);
+    assert_eq!(tree.next_chunk(0).map(|(k, _)| k.start), Some(10));
+    assert_eq!(tree.next_chunk(12).map(|(k, _)| k.start), Some(20));
+    assert!(tree.next_chunk(20).is_none());
+    assert_eq!(tree.prev_chunk(12).map(|(k, _)| k.start), Some(0));
+    assert_eq!(tree.prev_chunk(15).map(|(k, _)| k.start), Some(10));
+    assert!(tree.prev_chunk(4).is_none());
+}
+
+#[test]
+fn test_ctc_raid0() {
+    let chunk = ChunkTreeValue {
+        ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0,
+        stripe_len: 10,
+        sub_stripes: 0,
+        stripes: vec![
+            ChunkStripe {
+                devid: 1,
+                offset: 100,
+            },
+            ChunkStripe {
+                devid: 2,
+                offset: 200,
+            },
+        ],
+    };
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 40 }, chunk)
+        .unwrap();
+
+    assert_eq!(tree.physical(3), Some((1, 103)));
+    assert_eq!(tree.physical(13), Some((2, 203)));
+    assert_eq!(tree.physical(23), Some((1, 113)));
+    assert_eq!(tree.physical(39), Some((2, 219)));
+    assert_eq!(tree.map(13).unwrap().len, 7);
+}
+
+#[test]
+fn test_ctc_raid10() {
+    let chunk = ChunkTreeValue {
+        ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID10,
+        stripe_len: 10,
+        sub_stripes: 2,
+        stripes: (1..=4)
+            .map(|devid| ChunkStripe {
+                devid,
+                offset: devid * 100,
+            })
+            .collect(),
+    };
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 40 }, chunk)
+        .unwrap();
+
+    assert_eq!(tree.map(5).unwrap().mirrors, vec![(1, 105), (2, 205)]);
+    assert_eq!(tree.map(15).unwrap().mirrors, vec![(3, 305), (4, 405)]);
+    assert_eq!(tree.map(25).unwrap().mirrors, vec![(1, 115), (2, 215)]);
+    assert_eq!(tree.map(25).unwrap().num_copies(), 2);
+}
+
+#[test]
+fn test_ctc_raid5() {
+    let chunk = ChunkTreeValue {
+        ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID5,
+        stripe_len: 10,
+        sub_stripes: 0,
+        stripes: (1..=3)
+            .map(|devid| ChunkStripe {
+                devid,
+                offset: devid * 100,
+            })
+            .collect(),
+    };
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 40 }, chunk)
+        .unwrap();
+
+    // Row 0: D0 D1 P
+    let mapping = tree.map(12).unwrap();
+    assert_eq!(mapping.mirrors, vec![(2, 202)]);
+    assert_eq!(mapping.num_copies(), 2);
+    let parity = mapping.parity.unwrap();
+    assert_eq!(parity.data_index, 1);
+    assert_eq!(parity.data, vec![(1, 102), (2, 202)]);
+    assert_eq!(parity.p, (3, 302));
+    assert_eq!(parity.q, None);
 
-    // unreached
-    assert!(false);
+    // Row 1: P D0 D1
+    let parity = tree.map(21).unwrap().parity.unwrap();
+    assert_eq!(parity.data, vec![(2, 211), (3, 311)]);
+    assert_eq!(parity.p, (1, 111));
 }
@@ -0,0 +1,289 @@
+//! Decompression of compressed file extents
+
+use std::convert::TryInto;
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use ruzstd::decoding::StreamingDecoder;
+
+use crate::structs::*;
+
+/// Size of the length fields in btrfs's LZO framing
+const LZO_LEN: usize = 4;
+/// Compressed extents never hold more file data than this
+pub(crate) const BTRFS_MAX_UNCOMPRESSED: usize = 128 << 10;
+
+/// Decompress extent `data` compressed with `compression`, which holds `ram_bytes` of file
+/// data. `sector_size` is needed for the LZO framing. Like the kernel, output that comes up
+/// short is padded with zeros.
+pub fn decompress(
+    compression: u8,
+    data: &[u8],
+    ram_bytes: usize,
+    sector_size: usize,
+) -> Result<Vec<u8>> {
+    if ram_bytes > BTRFS_MAX_UNCOMPRESSED {
+        bail!("compressed extent claims to hold {} bytes", ram_bytes);
+    }
+
+    let mut out = match compression {
+        BTRFS_COMPRESS_ZLIB => {
+            let mut out = Vec::with_capacity(ram_bytes);
+            ZlibDecoder::new(data)
+                .take(ram_bytes as u64)
+                .read_to_end(&mut out)
+                .context("zlib decompression failed")?;
+            out
+        }
+        BTRFS_COMPRESS_LZO => lzo(data, ram_bytes, sector_size)?,
+        BTRFS_COMPRESS_ZSTD => {
+            let decoder = StreamingDecoder::new(data)
+                .map_err(|e| anyhow!("zstd decompression failed: {}", e))?;
+            let mut out = Vec::with_capacity(ram_bytes);
+            decoder
+                .take(ram_bytes as u64)
+                .read_to_end(&mut out)
+                .context("zstd decompression failed")?;
+            out
+        }
+        c => bail!("unknown compression={}", c),
+    };
+    out.resize(ram_bytes, 0);
+
+    Ok(out)
+}
+
+/// Undo btrfs's LZO framing: the total length, then segments of (length, LZO1X data) that
+/// each decompress to at most one sector. A segment header never straddles a sector
+/// boundary, the rest of the sector is zero padding instead.
+fn lzo(data: &[u8], ram_bytes: usize, sector_size: usize) -> Result<Vec<u8>> {
+    if sector_size == 0 {
+        bail!("sector size cannot be 0");
+    }
+
+    let read_len = |pos: usize| -> Result<usize> {
+        let bytes = data
+            .get(pos..pos + LZO_LEN)
+            .ok_or_else(|| anyhow!("LZO segment header at offset={} is truncated", pos))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+
+    let total_len = read_len(0)?;
+    if total_len > data.len() {
+        bail!(
+            "LZO data claims {} bytes but the extent has {}",
+            total_len,
+            data.len()
+        );
+    }
+
+    let mut out = Vec::with_capacity(ram_bytes);
+    let mut pos = LZO_LEN;
+    while pos < total_len && out.len() < ram_bytes {
+        let seg_len = read_len(pos)?;
+        pos += LZO_LEN;
+        let segment = data
+            .get(pos..pos + seg_len)
+            .ok_or_else(|| anyhow!("LZO segment at offset={} is truncated", pos))?;
+        lzo1x_decompress(segment, &mut out)
+            .with_context(|| format!("LZO segment at offset={} is corrupt", pos))?;
+        pos += seg_len;
+
+        let sector_left = sector_size - pos % sector_size;
+        if sector_left < LZO_LEN {
+            pos += sector_left;
+        }
+    }
+
+    Ok(out)
+}
+
+/// Append the LZO1X decompression of `src` to `out`. Follows the kernel's
+/// `lzo1x_decompress_safe`.
+fn lzo1x_decompress(src: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let mut ip = 0;
+    let byte = |ip: usize| -> Result<usize> {
+        src.get(ip)
+            .map(|b| *b as usize)
+            .ok_or_else(|| anyhow!("input overrun"))
+    };
+    // Runs of zero bytes extend a length by 255 each, then the next byte is added
+    let long_len = |ip: &mut usize| -> Result<usize> {
+        let mut len = 0;
+        while byte(*ip)? == 0 {
+            len += 255;
+            *ip += 1;
+        }
+        len += byte(*ip)?;
+        *ip += 1;
+        Ok(len)
+    };
+    let copy_literals = |ip: &mut usize, len: usize, out: &mut Vec<u8>| -> Result<()> {
+        let literals = src
+            .get(*ip..*ip + len)
+            .ok_or_else(|| anyhow!("input overrun"))?;
+        out.extend_from_slice(literals);
+        *ip += len;
+        Ok(())
+    };
+    let copy_match = |distance: usize, len: usize, out: &mut Vec<u8>| -> Result<()> {
+        if distance == 0 || distance > out.len() {
+            bail!("lookbehind overrun");
+        }
+        // Byte by byte, matches may overlap what they produce
+        let start = out.len() - distance;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+        Ok(())
+    };
+
+    // 0 after a match without trailing literals, 1-3 after a match with that many, 4 after
+    // a literal run
+    let mut state;
+    let first = byte(ip)?;
+    if first > 17 {
+        ip += 1;
+        let len = first - 17;
+        copy_literals(&mut ip, len, out)?;
+        state = if len < 4 { len } else { 4 };
+    } else {
+        state = 0;
+    }
+
+    loop {
+        let t = byte(ip)?;
+        ip += 1;
+
+        let (distance, len, next);
+        if t < 16 {
+            if state == 0 {
+                // Literal run
+                let len = if t == 0 { 15 + long_len(&mut ip)? } else { t };
+                copy_literals(&mut ip, len + 3, out)?;
+                state = 4;
+                continue;
+            }
+
+            next = t & 3;
+            let low = (t >> 2) + (byte(ip)? << 2);
+            ip += 1;
+            if state == 4 {
+                (distance, len) = (1 + 0x800 + low, 3);
+            } else {
+                (distance, len) = (1 + low, 2);
+            }
+        } else if t >= 64 {
+            next = t & 3;
+            distance = 1 + ((t >> 2) & 7) + (byte(ip)? << 3);
+            ip += 1;
+            len = (t >> 5) + 1;
+        } else if t >= 32 {
+            len = 2 + match t & 31 {
+                0 => 31 + long_len(&mut ip)?,
+                n => n,
+            };
+            let le16 = byte(ip)? | (byte(ip + 1)? << 8);
+            ip += 2;
+            distance = 1 + (le16 >> 2);
+            next = le16 & 3;
+        } else {
+            len = 2 + match t & 7 {
+                0 => 7 + long_len(&mut ip)?,
+                n => n,
+            };
+            let le16 = byte(ip)? | (byte(ip + 1)? << 8);
+            ip += 2;
+            let offset = ((t & 8) << 11) + (le16 >> 2);
+            if offset == 0 {
+                // End of stream marker
+                if len != 3 {
+                    bail!("bad end of stream marker");
+                }
+                return Ok(());
+            }
+            distance = offset + 0x4000;
+            next = le16 & 3;
+        }
+
+        copy_match(distance, len, out)?;
+        copy_literals(&mut ip, next, out)?;
+        state = next;
+    }
+}
+
+#[test]
+fn test_lzo1x_decompress() {
+    // "abcabcabcabcX": 3 literals, a match of 9 at distance 3, then 1 literal folded into the
+    // match, then the end of stream marker
+    let src = [
+        17 + 3,
+        b'a',
+        b'b',
+        b'c',
+        ((9 - 2) | 32) as u8,
+        ((3 - 1) << 2 | 1) as u8,
+        0,
+        b'X',
+        17,
+        0,
+        0,
+    ];
+    let mut out = Vec::new();
+    lzo1x_decompress(&src, &mut out).unwrap();
+    assert_eq!(out, b"abcabcabcabcX");
+
+    // Same with a M2 match
+    let src = [
+        17 + 3,
+        b'a',
+        b'b',
+        b'c',
+        ((4 - 1) << 5 | (3 - 1) << 2) as u8,
+        0,
+        17,
+        0,
+        0,
+    ];
+    let mut out = Vec::new();
+    lzo1x_decompress(&src, &mut out).unwrap();
+    assert_eq!(out, b"abcabca");
+
+    // Matches can't reach before the start of the output
+    let src = [17 + 1, b'a', ((4 - 1) << 5 | 2 << 2) as u8, 0, 17, 0, 0];
+    assert!(lzo1x_decompress(&src, &mut Vec::new()).is_err());
+}
+
+#[cfg(test)]
+fn lzo_literals(literals: &[u8]) -> Vec<u8> {
+    let mut segment = vec![17 + literals.len() as u8];
+    segment.extend_from_slice(literals);
+    segment.extend_from_slice(&[17, 0, 0]);
+    segment
+}
+
+#[test]
+fn test_lzo_framing() {
+    // With 32 byte sectors, the first segment ends 2 bytes before the sector boundary, so the
+    // second segment header starts at the next sector
+    let first = lzo_literals(b"0123456789abcdefgh");
+    let second = lzo_literals(b"world");
+    let mut data = Vec::new();
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(first.len() as u32).to_le_bytes());
+    data.extend_from_slice(&first);
+    assert_eq!(data.len(), 30);
+    data.extend_from_slice(&[0, 0]);
+    data.extend_from_slice(&(second.len() as u32).to_le_bytes());
+    data.extend_from_slice(&second);
+    let total_len = data.len() as u32;
+    data[..LZO_LEN].copy_from_slice(&total_len.to_le_bytes());
+    assert_eq!(
+        decompress(BTRFS_COMPRESS_LZO, &data, 25, 32).unwrap(),
+        b"0123456789abcdefghworld\0\0"
+    );
+
+    data[0] = 100;
+    assert!(decompress(BTRFS_COMPRESS_LZO, &data, 25, 32).is_err());
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use sha2::Sha256;
+
+use crate::structs::BTRFS_CSUM_SIZE;
+
+pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+pub const BTRFS_CSUM_TYPE_XXHASH: u16 = 1;
+pub const BTRFS_CSUM_TYPE_SHA256: u16 = 2;
+pub const BTRFS_CSUM_TYPE_BLAKE2: u16 = 3;
+
+/// Checksum algorithm used for metadata, as selected by `BtrfsSuperblock.csum_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsumType {
+    Crc32c,
+    Xxhash64,
+    Sha256,
+    Blake2b,
+}
+
+impl CsumType {
+    pub fn from_raw(ty: u16) -> Result<Self> {
+        Ok(match ty {
+            BTRFS_CSUM_TYPE_CRC32 => CsumType::Crc32c,
+            BTRFS_CSUM_TYPE_XXHASH => CsumType::Xxhash64,
+            BTRFS_CSUM_TYPE_SHA256 => CsumType::Sha256,
+            BTRFS_CSUM_TYPE_BLAKE2 => CsumType::Blake2b,
+            _ => bail!("unknown csum_type={}", ty),
+        })
+    }
+
+    /// Number of meaningful bytes at the start of a `[u8; BTRFS_CSUM_SIZE]` csum field
+    pub fn size(self) -> usize {
+        match self {
+            CsumType::Crc32c => 4,
+            CsumType::Xxhash64 => 8,
+            CsumType::Sha256 | CsumType::Blake2b => 32,
+        }
+    }
+
+    /// Compute checksum of `data`. Unused trailing bytes are zeroed, same as on disk.
+    pub fn compute(self, data: &[u8]) -> [u8; BTRFS_CSUM_SIZE] {
+        let mut out = [0; BTRFS_CSUM_SIZE];
+        match self {
+            CsumType::Crc32c => out[..4].copy_from_slice(&crc32c::crc32c(data).to_le_bytes()),
+            CsumType::Xxhash64 => {
+                out[..8].copy_from_slice(&xxhash_rust::xxh64::xxh64(data, 0).to_le_bytes())
+            }
+            CsumType::Sha256 => out.copy_from_slice(&Sha256::digest(data)),
+            // btrfs uses the 256 bit variant of BLAKE2b
+            CsumType::Blake2b => out.copy_from_slice(&Blake2b::<U32>::digest(data)),
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for CsumType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CsumType::Crc32c => "crc32c",
+            CsumType::Xxhash64 => "xxhash64",
+            CsumType::Sha256 => "sha256",
+            CsumType::Blake2b => "blake2b",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Returned (wrapped in `anyhow::Error`) when on-disk metadata does not match its checksum
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    /// What kind of block failed, eg. "superblock" or "tree block"
+    pub what: &'static str,
+    pub csum_type: CsumType,
+    pub logical: u64,
+    pub physical: u64,
+    pub expected: Vec<u8>,
+    pub found: Vec<u8>,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} checksum mismatch at logical bytenr={}, physical bytenr={}: {} expected={} found={}",
+            self.what,
+            self.logical,
+            self.physical,
+            self.csum_type,
+            hex(&self.expected),
+            hex(&self.found),
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Verify the checksum stored in the first `BTRFS_CSUM_SIZE` bytes of `buf` against the rest
+/// of `buf`. Superblocks and tree blocks share this layout.
+pub fn verify(
+    csum_type: CsumType,
+    buf: &[u8],
+    what: &'static str,
+    logical: u64,
+    physical: u64,
+) -> Result<()> {
+    if buf.len() < BTRFS_CSUM_SIZE {
+        bail!("{} too small to hold a checksum", what);
+    }
+
+    let size = csum_type.size();
+    let computed = csum_type.compute(&buf[BTRFS_CSUM_SIZE..]);
+    if computed[..size] != buf[..size] {
+        return Err(ChecksumMismatch {
+            what,
+            csum_type,
+            logical,
+            physical,
+            expected: buf[..size].to_vec(),
+            found: computed[..size].to_vec(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_csum_known_values() {
+    let crc = CsumType::Crc32c.compute(b"123456789");
+    assert_eq!(crc[..4], 0xe306_9283_u32.to_le_bytes());
+    assert!(crc[4..].iter().all(|b| *b == 0));
+
+    let xxh = CsumType::Xxhash64.compute(b"");
+    assert_eq!(xxh[..8], 0xef46_db37_51d8_e999_u64.to_le_bytes());
+
+    let sha = CsumType::Sha256.compute(b"abc");
+    assert_eq!(
+        hex(&sha),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[test]
+fn test_csum_verify() {
+    for ty in 0..=3 {
+        let csum_type = CsumType::from_raw(ty).unwrap();
+        let mut buf = vec![0xab; 4096];
+        let csum = csum_type.compute(&buf[BTRFS_CSUM_SIZE..]);
+        buf[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+        assert!(verify(csum_type, &buf, "tree block", 1, 2).is_ok());
+
+        buf[100] ^= 1;
+        let err = verify(csum_type, &buf, "tree block", 1, 2).unwrap_err();
+        let mismatch = err.downcast_ref::<ChecksumMismatch>().unwrap();
+        assert_eq!(mismatch.logical, 1);
+        assert_eq!(mismatch.physical, 2);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::filesystem::Warnings;
+use crate::structs::*;
+use crate::superblock;
+
+/// One of the block devices or images a filesystem is made of
+pub struct Device {
+    /// Where the device was opened from
+    pub path: PathBuf,
+    /// Superblock read from this device
+    pub superblock: BtrfsSuperblock,
+    file: File,
+}
+
+impl Device {
+    /// Internal btrfs device id, as referenced by `BtrfsStripe.devid`
+    pub fn devid(&self) -> u64 {
+        self.superblock.dev_item.devid
+    }
+
+    /// Device uuid, as referenced by `BtrfsStripe.dev_uuid`
+    pub fn uuid(&self) -> [u8; BTRFS_UUID_SIZE] {
+        self.superblock.dev_item.uuid
+    }
+}
+
+/// All devices of a filesystem that were found, keyed by devid
+pub struct Devices {
+    inner: BTreeMap<u64, Device>,
+}
+
+impl Devices {
+    /// Open every device in `paths` and make sure they belong to the same filesystem. Returns
+    /// the devices along with the newest superblock among them.
+    pub(crate) fn open<P: AsRef<Path>>(
+        paths: &[P],
+        super_mirror: Option<usize>,
+        warnings: &mut Warnings,
+    ) -> Result<(Self, BtrfsSuperblock)> {
+        if paths.is_empty() {
+            bail!("no devices given");
+        }
+
+        let mut inner: BTreeMap<u64, Device> = BTreeMap::new();
+        let mut newest: Option<BtrfsSuperblock> = None;
+        for path in paths {
+            let path = path.as_ref();
+            let file = OpenOptions::new()
+                .read(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+
+            let mut device_warnings = Vec::new();
+            let superblock =
+                superblock::parse_superblock(&file, super_mirror, &mut device_warnings)
+                    .with_context(|| format!("failed to read superblock of {}", path.display()))?;
+            for warning in device_warnings {
+                warnings.push(format!("{}: {}", path.display(), warning));
+            }
+
+            if let Some(newest) = &newest {
+                if superblock.fsid != newest.fsid {
+                    bail!(
+                        "{} belongs to filesystem {}, expected {}",
+                        path.display(),
+                        format_uuid(&superblock.fsid),
+                        format_uuid(&newest.fsid)
+                    );
+                }
+            }
+
+            let device = Device {
+                path: path.to_path_buf(),
+                superblock,
+                file,
+            };
+            if let Some(other) = inner.get(&device.devid()) {
+                bail!(
+                    "{} and {} both claim to be devid={}",
+                    other.path.display(),
+                    path.display(),
+                    device.devid()
+                );
+            }
+
+            if newest.is_none_or(|n| superblock.generation > n.generation) {
+                newest = Some(superblock);
+            }
+            inner.insert(device.devid(), device);
+        }
+
+        let newest = newest.unwrap();
+        for device in inner.values() {
+            if device.superblock.generation != newest.generation {
+                warnings.push(format!(
+                    "{} has generation={} but the newest device has generation={}",
+                    device.path.display(),
+                    device.superblock.generation,
+                    newest.generation
+                ));
+            }
+        }
+
+        Ok((Self { inner }, newest))
+    }
+
+    pub fn get(&self, devid: u64) -> Option<&Device> {
+        self.inner.get(&devid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.inner.values()
+    }
+
+    /// Read `buf.len()` bytes at `physical` on device `devid`
+    pub fn read_exact_at(&self, devid: u64, buf: &mut [u8], physical: u64) -> Result<()> {
+        let device = self
+            .get(devid)
+            .ok_or_else(|| anyhow!("device devid={} is missing", devid))?;
+        device.file.read_exact_at(buf, physical).with_context(|| {
+            format!(
+                "failed to read {} bytes at physical={} from {}",
+                buf.len(),
+                physical,
+                device.path.display()
+            )
+        })
+    }
+
+    /// Compare the devices found against the DEV_ITEMs in the chunk tree. Returns the DEV_ITEMs
+    /// of devices that are missing.
+    pub(crate) fn check_dev_items(&self, dev_items: &[BtrfsDevItem]) -> Result<Vec<BtrfsDevItem>> {
+        let mut missing = Vec::new();
+        for dev_item in dev_items {
+            match self.get(dev_item.devid) {
+                Some(device) if device.uuid() != dev_item.uuid => bail!(
+                    "{} has devid={} uuid={}, but the chunk tree expects uuid={}",
+                    device.path.display(),
+                    dev_item.devid,
+                    format_uuid(&device.uuid()),
+                    format_uuid(&dev_item.uuid)
+                ),
+                Some(_) => (),
+                None => missing.push(*dev_item),
+            }
+        }
+
+        Ok(missing)
+    }
+}
+
+/// Format a uuid the usual way, eg. 01234567-89ab-cdef-0123-456789abcdef
+pub fn format_uuid(uuid: &[u8; BTRFS_UUID_SIZE]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[test]
+fn test_format_uuid() {
+    let uuid = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+    assert_eq!(format_uuid(&uuid), "01234567-89ab-cdef-0123-456789abcdef");
+}
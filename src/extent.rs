@@ -0,0 +1,283 @@
+//! File contents, assembled from the EXTENT_DATA items of an inode
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::compression;
+use crate::file_type::FileType;
+use crate::filesystem::Filesystem;
+use crate::parse::{Parse, Reader};
+use crate::structs::*;
+
+/// How much of a regular extent is read from disk at once
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+/// One EXTENT_DATA item of a file
+enum FileExtent {
+    /// Data stored in the item itself
+    Inline {
+        compression: u8,
+        ram_bytes: u64,
+        data: Vec<u8>,
+    },
+    /// Data stored in an extent on disk. `disk.disk_bytenr == 0` is a hole.
+    Regular {
+        compression: u8,
+        ram_bytes: u64,
+        disk: BtrfsFileExtentDisk,
+    },
+    /// Allocated but never written, reads as zeros
+    Prealloc { num_bytes: u64 },
+}
+
+impl FileExtent {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let item = BtrfsFileExtentItem::read(&mut reader)?;
+        if item.encryption != 0 || item.other_encoding != 0 {
+            bail!(
+                "extent has encryption={} other_encoding={}, which are not supported",
+                item.encryption,
+                item.other_encoding
+            );
+        }
+
+        Ok(match item.ty {
+            BTRFS_FILE_EXTENT_INLINE => FileExtent::Inline {
+                compression: item.compression,
+                ram_bytes: item.ram_bytes,
+                data: reader.bytes(reader.remaining())?.to_vec(),
+            },
+            BTRFS_FILE_EXTENT_REG => FileExtent::Regular {
+                compression: item.compression,
+                ram_bytes: item.ram_bytes,
+                disk: BtrfsFileExtentDisk::read(&mut reader)?,
+            },
+            BTRFS_FILE_EXTENT_PREALLOC => FileExtent::Prealloc {
+                num_bytes: BtrfsFileExtentDisk::read(&mut reader)?.num_bytes,
+            },
+            ty => bail!("unknown file extent type={}", ty),
+        })
+    }
+}
+
+impl Filesystem {
+    /// Write the contents of file `inode` to `out`. Holes and preallocated ranges are written
+    /// as zeros. Returns the number of bytes written, which is the size of the file. Only
+    /// regular files and symlinks have contents.
+    pub fn read_file<W: Write>(&self, inode: u64, out: &mut W) -> Result<u64> {
+        let inode_item = self.inode_item(inode)?;
+        match FileType::from_mode(inode_item.mode) {
+            FileType::Regular | FileType::Symlink => {}
+            ty => bail!("inode={} is not a regular file, type={}", inode, ty),
+        }
+        let size = inode_item.size;
+        let mut pos = 0;
+        for (key, data) in self.fs_tree_items(inode, BTRFS_EXTENT_DATA_KEY)? {
+            // Preallocated extents can extend past the end of the file
+            if key.offset >= size {
+                break;
+            }
+            if key.offset < pos {
+                bail!(
+                    "extent at offset={} of inode={} overlaps the previous extent",
+                    key.offset,
+                    inode
+                );
+            }
+            write_zeros(out, key.offset - pos)?;
+            pos = key.offset;
+
+            let extent = FileExtent::parse(&data)
+                .with_context(|| format!("bad extent at offset={} of inode={}", pos, inode))?;
+            pos += self
+                .write_extent(&extent, size - pos, out)
+                .with_context(|| {
+                    format!("failed to read extent at offset={} of inode={}", pos, inode)
+                })?;
+        }
+        write_zeros(out, size - pos)?;
+
+        Ok(size)
+    }
+
+    /// Read the target of symlink `inode`
+    pub fn read_link(&self, inode: u64) -> Result<Vec<u8>> {
+        let mut target = Vec::new();
+        self.read_file(inode, &mut target)?;
+
+        Ok(target)
+    }
+
+    fn decompress(&self, compression: u8, data: &[u8], ram_bytes: u64) -> Result<Vec<u8>> {
+        compression::decompress(
+            compression,
+            data,
+            ram_bytes as usize,
+            self.superblock().sector_size as usize,
+        )
+    }
+
+    /// Write at most `max_len` bytes of `extent` to `out`. Returns how many were written.
+    fn write_extent<W: Write>(
+        &self,
+        extent: &FileExtent,
+        max_len: u64,
+        out: &mut W,
+    ) -> Result<u64> {
+        match extent {
+            FileExtent::Inline {
+                compression,
+                ram_bytes,
+                data,
+            } => {
+                let data = if *compression == BTRFS_COMPRESS_NONE {
+                    data.clone()
+                } else {
+                    self.decompress(*compression, data, *ram_bytes)?
+                };
+                let len = (data.len() as u64).min(max_len);
+                out.write_all(&data[..len as usize])?;
+                Ok(len)
+            }
+            FileExtent::Regular {
+                compression,
+                ram_bytes,
+                disk,
+            } => {
+                let len = disk.num_bytes.min(max_len);
+                if disk.disk_bytenr == 0 {
+                    write_zeros(out, len)?;
+                    return Ok(len);
+                }
+
+                if *compression != BTRFS_COMPRESS_NONE {
+                    // `offset` and `num_bytes` refer to the decompressed data, so the whole
+                    // extent has to be decompressed. Compressed data is never larger than
+                    // what it decompresses to, check that before allocating.
+                    if disk.disk_num_bytes > compression::BTRFS_MAX_UNCOMPRESSED as u64 {
+                        bail!(
+                            "compressed extent claims to take {} bytes on disk",
+                            disk.disk_num_bytes
+                        );
+                    }
+                    let mut compressed = vec![0; disk.disk_num_bytes as usize];
+                    self.read_logical(disk.disk_bytenr, &mut compressed)?;
+                    let data = self.decompress(*compression, &compressed, *ram_bytes)?;
+                    let start = usize::try_from(disk.offset).ok();
+                    let end = disk
+                        .offset
+                        .checked_add(len)
+                        .and_then(|end| usize::try_from(end).ok());
+                    let range = start
+                        .zip(end)
+                        .and_then(|(start, end)| data.get(start..end))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "extent offset={} num_bytes={} is outside ram_bytes={}",
+                                disk.offset,
+                                disk.num_bytes,
+                                ram_bytes
+                            )
+                        })?;
+                    out.write_all(range)?;
+                    return Ok(len);
+                }
+
+                let mut buf = vec![0; READ_CHUNK_SIZE];
+                let mut done = 0;
+                while done < len {
+                    let chunk = (len - done).min(buf.len() as u64) as usize;
+                    let logical = disk
+                        .offset
+                        .checked_add(done)
+                        .and_then(|offset| disk.disk_bytenr.checked_add(offset))
+                        .ok_or_else(|| anyhow!("extent offset={} is too large", disk.offset))?;
+                    self.read_logical(logical, &mut buf[..chunk])?;
+                    out.write_all(&buf[..chunk])?;
+                    done += chunk as u64;
+                }
+
+                Ok(len)
+            }
+            FileExtent::Prealloc { num_bytes } => {
+                let len = (*num_bytes).min(max_len);
+                write_zeros(out, len)?;
+                Ok(len)
+            }
+        }
+    }
+}
+
+fn write_zeros<W: Write>(out: &mut W, len: u64) -> Result<()> {
+    std::io::copy(&mut std::io::repeat(0).take(len), out)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_file_extent() {
+    let mut inline = vec![0; BtrfsFileExtentItem::SIZE];
+    inline[8] = 5; // ram_bytes
+    inline.extend_from_slice(b"hello");
+    match FileExtent::parse(&inline).unwrap() {
+        FileExtent::Inline {
+            compression, data, ..
+        } => {
+            assert_eq!(compression, BTRFS_COMPRESS_NONE);
+            assert_eq!(data, b"hello");
+        }
+        _ => panic!("expected an inline extent"),
+    }
+
+    let mut prealloc = vec![0; BtrfsFileExtentItem::SIZE + BtrfsFileExtentDisk::SIZE];
+    prealloc[20] = BTRFS_FILE_EXTENT_PREALLOC;
+    prealloc[46] = 0x10; // num_bytes
+    assert!(matches!(
+        FileExtent::parse(&prealloc).unwrap(),
+        FileExtent::Prealloc { num_bytes: 0x1000 }
+    ));
+
+    // Regular extents need the on-disk location
+    prealloc[20] = BTRFS_FILE_EXTENT_REG;
+    assert!(FileExtent::parse(&prealloc[..30]).is_err());
+}
+
+#[test]
+fn test_read_file() {
+    use crate::filesystem::{test_image, test_inode_item};
+
+    let key = |objectid, ty| BtrfsKey {
+        objectid,
+        ty,
+        offset: 0,
+    };
+    let mut extent = vec![0; BtrfsFileExtentItem::SIZE];
+    extent[8] = 3; // ram_bytes
+    extent.extend_from_slice(b"hi\n");
+    let path = test_image(
+        "read-file",
+        &[
+            (key(256, BTRFS_INODE_ITEM_KEY), test_inode_item(0o40755, 20)),
+            (key(257, BTRFS_INODE_ITEM_KEY), test_inode_item(0o100644, 3)),
+            (key(257, BTRFS_EXTENT_DATA_KEY), extent),
+        ],
+    );
+    let fs = Filesystem::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut out = Vec::new();
+    assert_eq!(fs.read_file(257, &mut out).unwrap(), 3);
+    assert_eq!(out, b"hi\n");
+
+    // A directory has a size but no contents
+    out.clear();
+    let e = fs.read_file(256, &mut out).unwrap_err();
+    assert_eq!(
+        format!("{:#}", e),
+        "inode=256 is not a regular file, type=d"
+    );
+    assert!(out.is_empty());
+}
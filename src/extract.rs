@@ -0,0 +1,283 @@
+//! Copying files and directory trees out of the filesystem onto the host
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::BufWriter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::file_type::FileType;
+use crate::filesystem::Filesystem;
+
+/// Permission bits of an inode mode
+const MODE_PERMISSIONS: u32 = 0o7777;
+
+/// Knobs for `Filesystem::extract_with_options`
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Restore extended attributes, including ACLs, file capabilities and security labels.
+    /// Attributes that can't be read or that the host refuses are skipped with a warning.
+    pub xattrs: bool,
+}
+
+impl Filesystem {
+    /// Copy the file, symlink or directory tree at absolute `path` to `dest` on the host.
+    /// `dest` must not exist yet. Subvolumes below `path` are extracted as directories. File
+    /// types that can't be extracted are skipped with a warning.
+    pub fn extract<S: AsRef<Path>, P: AsRef<Path>>(&self, path: S, dest: P) -> Result<()> {
+        self.extract_with_options(path, dest, &ExtractOptions::default())
+    }
+
+    /// Like `extract`, with `options`
+    pub fn extract_with_options<S: AsRef<Path>, P: AsRef<Path>>(
+        &self,
+        path: S,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let (fs, inode) = self.resolve(path)?;
+        let ty = FileType::from_mode(fs.inode_item(inode)?.mode);
+        let mut visited = HashSet::new();
+        fs.extract_inode(inode, ty, path, dest.as_ref(), &mut visited, options)
+    }
+
+    fn extract_inode(
+        &self,
+        inode: u64,
+        ty: FileType,
+        path: &Path,
+        dest: &Path,
+        visited: &mut HashSet<(u64, u64)>,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        match ty {
+            FileType::Regular => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(dest)
+                    .with_context(|| format!("failed to create {}", dest.display()))?;
+                let mut out = BufWriter::new(file);
+                self.read_file(inode, &mut out)
+                    .with_context(|| format!("failed to extract {}", path.display()))?;
+                let file = out.into_inner()?;
+                self.restore_xattrs(inode, path, dest, options)?;
+                self.set_permissions(inode, &file)?;
+            }
+            FileType::Directory => {
+                if !visited.insert((self.subvol_id(), inode)) {
+                    bail!("directory loop detected at {}", path.display());
+                }
+
+                fs::create_dir(dest)
+                    .with_context(|| format!("failed to create {}", dest.display()))?;
+                for entry in self.read_dir(inode)? {
+                    // The name is joined onto a host path, it must not lead out of `dest`
+                    if let Err(e) = check_entry_name(&entry.name) {
+                        self.warn(format!(
+                            "skipping {:?} in {}: {:#}",
+                            entry.name,
+                            path.display(),
+                            e
+                        ));
+                        continue;
+                    }
+                    let fs = match entry.subvol {
+                        Some(subvol) => self.subvolume(subvol)?,
+                        None => self.clone(),
+                    };
+                    fs.extract_inode(
+                        entry.inode,
+                        entry.ty,
+                        &path.join(&entry.name),
+                        &dest.join(&entry.name),
+                        visited,
+                        options,
+                    )?;
+                }
+                // Last, in case the directory is read-only
+                self.restore_xattrs(inode, path, dest, options)?;
+                self.set_permissions(inode, &File::open(dest)?)?;
+            }
+            FileType::Symlink => {
+                let target = self.read_link(inode)?;
+                symlink(OsStr::from_bytes(&target), dest)
+                    .with_context(|| format!("failed to create {}", dest.display()))?;
+                self.restore_xattrs(inode, path, dest, options)?;
+            }
+            ty => {
+                self.warn(format!(
+                    "skipping {}: file type={} can't be extracted",
+                    path.display(),
+                    ty
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the extended attributes of `inode` to `dest` if `options` ask for it, without
+    /// following symlinks. Needs to happen before permissions are set, read-only files can't
+    /// get attributes without root. Attributes that can't be read or set are skipped with a
+    /// warning.
+    fn restore_xattrs(
+        &self,
+        inode: u64,
+        path: &Path,
+        dest: &Path,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        if !options.xattrs {
+            return Ok(());
+        }
+
+        let xattrs = match self.xattrs(inode) {
+            Ok(xattrs) => xattrs,
+            Err(e) => {
+                self.warn(format!(
+                    "failed to read xattrs of {}: {:#}",
+                    path.display(),
+                    e
+                ));
+                return Ok(());
+            }
+        };
+        for attr in xattrs {
+            if let Err(e) = xattr::set(dest, &attr.name, &attr.value) {
+                self.warn(format!(
+                    "failed to restore xattr {:?} of {}: {}",
+                    attr.name,
+                    path.display(),
+                    e
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_permissions(&self, inode: u64, file: &File) -> Result<()> {
+        let mode = self.inode_item(inode)?.mode & MODE_PERMISSIONS;
+        file.set_permissions(Permissions::from_mode(mode))?;
+
+        Ok(())
+    }
+}
+
+/// Make sure `name` is a single path component, as directory entry names always are on a
+/// healthy filesystem
+fn check_entry_name(name: &OsStr) -> Result<()> {
+    match name.as_bytes() {
+        b"" => bail!("empty name"),
+        b"." | b".." => bail!("name is a relative path component"),
+        name if name.contains(&b'/') => bail!("name contains '/'"),
+        name if name.contains(&0) => bail!("name contains a NUL byte"),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_check_entry_name() {
+    let good: [&[u8]; 5] = [b"file", b"..hidden", b"a.b", b"sp ace", b"caf\xff"];
+    for name in good {
+        assert!(
+            check_entry_name(OsStr::from_bytes(name)).is_ok(),
+            "{:?}",
+            name
+        );
+    }
+    let crafted = [
+        "",
+        ".",
+        "..",
+        "../../escaped",
+        "/etc/passwd",
+        "dir/",
+        "a\0b",
+    ];
+    for name in crafted {
+        assert!(check_entry_name(OsStr::new(name)).is_err(), "{:?}", name);
+    }
+}
+
+#[test]
+fn test_extract_bad_xattrs() {
+    use crate::filesystem::{test_image, test_inode_item};
+    use crate::parse::Parse;
+    use crate::structs::*;
+    use crate::tree::name_hash;
+
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    // A DIR_ITEM, or an XATTR_ITEM if there is a value
+    let item = |inode: u64, ty: u8, name: &[u8], value: &[u8]| {
+        let mut data = vec![0; BtrfsDirItem::SIZE];
+        data[..8].copy_from_slice(&inode.to_le_bytes());
+        data[8] = BTRFS_INODE_ITEM_KEY;
+        data[25..27].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        data[27..29].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        data[29] = ty;
+        data.extend_from_slice(name);
+        data.extend_from_slice(value);
+        data
+    };
+    let xattr_name = b"user.caf\xff";
+    let mut items = vec![
+        (
+            key(256, BTRFS_INODE_ITEM_KEY, 0),
+            test_inode_item(0o40700, 2),
+        ),
+        (
+            key(257, BTRFS_INODE_ITEM_KEY, 0),
+            test_inode_item(0o100600, 0),
+        ),
+        (
+            key(258, BTRFS_INODE_ITEM_KEY, 0),
+            test_inode_item(0o100640, 0),
+        ),
+        (
+            key(257, BTRFS_XATTR_ITEM_KEY, name_hash(xattr_name)),
+            item(0, BTRFS_FT_XATTR, xattr_name, b"v"),
+        ),
+        // Cut short in the middle of the name
+        (
+            key(258, BTRFS_XATTR_ITEM_KEY, name_hash(b"user.a")),
+            item(0, BTRFS_FT_XATTR, b"user.a", b"v")[..32].to_vec(),
+        ),
+    ];
+    for (inode, name) in [(257, b"raw_name"), (258, b"bad_item")] {
+        items.push((
+            key(256, BTRFS_DIR_ITEM_KEY, name_hash(name)),
+            item(inode, BTRFS_FT_REG_FILE, name, b""),
+        ));
+    }
+    items.sort_by_key(|(key, _)| (key.objectid, key.ty, key.offset));
+    let path = test_image("extract-bad-xattrs", &items);
+    let fs = Filesystem::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Both files are extracted with their permissions despite the xattrs
+    let dest = std::env::temp_dir().join(format!("btrfs-walk-xattrs-{}", std::process::id()));
+    let options = ExtractOptions { xattrs: true };
+    fs.extract_with_options("/", &dest, &options).unwrap();
+    let mode = |name| fs::metadata(dest.join(name)).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode("raw_name"), 0o600);
+    assert_eq!(mode("bad_item"), 0o640);
+    let names = fs.xattrs(257).unwrap();
+    assert_eq!(names[0].name, OsStr::from_bytes(xattr_name));
+    assert!(fs
+        .warnings()
+        .iter()
+        .any(|warning| warning.starts_with("failed to read xattrs of /bad_item")));
+    fs::set_permissions(&dest, Permissions::from_mode(0o700)).unwrap();
+    fs::remove_dir_all(&dest).unwrap();
+}
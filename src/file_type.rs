@@ -0,0 +1,103 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::structs::*;
+
+/// Type of a directory entry, from `BtrfsDirItem.ty` or an inode mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+    /// Any other `BTRFS_FT_*` value
+    Unknown(u8),
+}
+
+impl FileType {
+    pub fn from_raw(ty: u8) -> Self {
+        match ty {
+            BTRFS_FT_REG_FILE => FileType::Regular,
+            BTRFS_FT_DIR => FileType::Directory,
+            BTRFS_FT_CHRDEV => FileType::CharDevice,
+            BTRFS_FT_BLKDEV => FileType::BlockDevice,
+            BTRFS_FT_FIFO => FileType::Fifo,
+            BTRFS_FT_SOCK => FileType::Socket,
+            BTRFS_FT_SYMLINK => FileType::Symlink,
+            ty => FileType::Unknown(ty),
+        }
+    }
+
+    /// Type from the `S_IFMT` bits of an inode mode
+    pub fn from_mode(mode: u32) -> Self {
+        match mode & 0o170000 {
+            0o100000 => FileType::Regular,
+            0o040000 => FileType::Directory,
+            0o020000 => FileType::CharDevice,
+            0o060000 => FileType::BlockDevice,
+            0o010000 => FileType::Fifo,
+            0o140000 => FileType::Socket,
+            0o120000 => FileType::Symlink,
+            _ => FileType::Unknown(0),
+        }
+    }
+
+    /// Single letter, same as `find -type`
+    pub fn indicator(self) -> char {
+        match self {
+            FileType::Regular => 'f',
+            FileType::Directory => 'd',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+            FileType::Symlink => 'l',
+            FileType::Unknown(_) => '?',
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.indicator())
+    }
+}
+
+impl FromStr for FileType {
+    type Err = Error;
+
+    /// Parse a `find -type` letter
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "f" => FileType::Regular,
+            "d" => FileType::Directory,
+            "c" => FileType::CharDevice,
+            "b" => FileType::BlockDevice,
+            "p" => FileType::Fifo,
+            "s" => FileType::Socket,
+            "l" => FileType::Symlink,
+            _ => {
+                return Err(anyhow!(
+                    "unknown file type '{}', expected one of fdcbpsl",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+#[test]
+fn test_file_type() {
+    for raw in 1..=7 {
+        let ty = FileType::from_raw(raw);
+        assert_eq!(ty.indicator().to_string().parse::<FileType>().unwrap(), ty);
+    }
+    assert_eq!(FileType::from_raw(42), FileType::Unknown(42));
+    assert_eq!(FileType::from_mode(0o120777), FileType::Symlink);
+    assert!("x".parse::<FileType>().is_err());
+}
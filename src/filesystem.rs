@@ -0,0 +1,1637 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, trace, warn};
+use lru::LruCache;
+
+use crate::chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeValue, StripeMapping};
+use crate::csum::{self, CsumType};
+use crate::device::{format_uuid, Devices};
+use crate::file_type::FileType;
+use crate::parse::{Parse, Reader};
+use crate::raid;
+use crate::structs::*;
+use crate::subvolume::{self, Subvolume};
+use crate::superblock::{self, TreeRoots};
+use crate::tree;
+
+/// Knobs for `Filesystem::open_with_options` and `Filesystem::open_devices`
+#[derive(Debug, Default, Clone)]
+pub struct FilesystemOptions {
+    /// Use this superblock copy (0 is the primary, 1 and 2 are mirrors) instead of the valid
+    /// copy with the highest generation
+    pub super_mirror: Option<usize>,
+    /// Use this superblock backup root slot (0-3) instead of the current tree roots
+    pub backup_root: Option<usize>,
+    /// Memory to spend on caching tree blocks, in bytes. 0 disables the cache. Defaults to
+    /// `DEFAULT_BLOCK_CACHE_SIZE`.
+    pub block_cache_size: Option<usize>,
+}
+
+/// Tree block cache budget when `FilesystemOptions::block_cache_size` is not set
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 64 << 20;
+
+/// An unmounted btrfs filesystem. Each handle reads files from one subvolume, the top level
+/// one unless it came from `Filesystem::subvolume`. Handles are cheap to clone and share their
+/// devices, caches and warnings.
+///
+/// That sharing goes through `Rc` and `RefCell` without locking, so handles are neither `Send`
+/// nor `Sync`. To read from several threads, open the filesystem once in each of them.
+///
+/// ```compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+/// assert_send::<btrfs_walk::Filesystem>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<btrfs_walk::Filesystem>();
+/// ```
+#[derive(Clone)]
+pub struct Filesystem {
+    shared: Rc<Shared>,
+    /// Subvolume files are read from
+    subvol: u64,
+    /// Logical address of the subvolume's fs tree root node
+    fs_tree_root: u64,
+    /// Absolute path of the subvolume's top directory, empty for the top level subvolume
+    subvol_path: OsString,
+}
+
+/// State shared by the handles on every subvolume of a filesystem
+struct Shared {
+    devices: Devices,
+    /// Newest superblock among all devices
+    superblock: BtrfsSuperblock,
+    /// DEV_ITEMs of devices that were not found
+    missing_devices: Vec<BtrfsDevItem>,
+    chunk_tree_cache: ChunkTreeCache,
+    tree_roots: TreeRoots,
+    /// Grows as damaged blocks are worked around while walking trees
+    warnings: RefCell<Warnings>,
+    block_cache: BlockCache,
+    /// Every subvolume, loaded on first use
+    subvolumes: RefCell<Option<Vec<Subvolume>>>,
+}
+
+/// Problems that were worked around. Each one is logged as soon as it is raised, so it is not
+/// lost if reading fails later on.
+#[derive(Default)]
+pub(crate) struct Warnings {
+    /// Every warning raised, in order
+    list: Vec<String>,
+    /// The same block may be read many times, warnings already raised are only logged once
+    seen: HashSet<String>,
+}
+
+impl Warnings {
+    /// Log `warning` and record it, unless it was already raised
+    pub(crate) fn push(&mut self, warning: String) {
+        if self.seen.insert(warning.clone()) {
+            warn!("{}", warning);
+            self.list.push(warning);
+        }
+    }
+
+    fn as_slice(&self) -> &[String] {
+        &self.list
+    }
+}
+
+/// Recently used tree blocks that passed validation, by logical address
+struct BlockCache {
+    /// `None` if caching is disabled
+    lru: Option<RefCell<LruCache<u64, Rc<[u8]>>>>,
+}
+
+impl BlockCache {
+    /// Cache as many blocks of `node_size` bytes as fit in `cache_size` bytes. Caching is
+    /// disabled if not even one fits.
+    fn new(cache_size: usize, node_size: usize) -> Self {
+        // Grows as blocks are read, a large budget doesn't allocate room for all of them up front
+        let lru = NonZeroUsize::new(cache_size / node_size).map(|blocks| {
+            let mut lru = LruCache::unbounded();
+            lru.resize(blocks);
+            RefCell::new(lru)
+        });
+
+        Self { lru }
+    }
+
+    /// Return the block at `logical` from the cache, or get it with `read` and cache it
+    fn get_or_read<R>(&self, logical: u64, read: R) -> Result<Rc<[u8]>>
+    where
+        R: FnOnce() -> Result<Rc<[u8]>>,
+    {
+        let lru = match &self.lru {
+            Some(lru) => lru,
+            None => return read(),
+        };
+        if let Some(node) = lru.borrow_mut().get(&logical) {
+            trace!("bytenr={} found in the block cache", logical);
+            return Ok(node.clone());
+        }
+
+        // Not borrowed while reading, `read` may come back for other blocks
+        let node = read()?;
+        lru.borrow_mut().put(logical, node.clone());
+
+        Ok(node)
+    }
+
+    /// Number of blocks cached
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.lru.as_ref().map_or(0, |lru| lru.borrow().len())
+    }
+}
+
+/// A file, directory or other entry found in the fs tree
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Absolute path of the entry. Names are raw bytes, which need not be UTF-8.
+    pub path: OsString,
+    /// Subvolume holding the inode. For the mount point of a subvolume, that is the subvolume
+    /// itself and the inode is its top directory.
+    pub subvol: u64,
+    /// Inode number of the entry
+    pub inode: u64,
+    pub ty: FileType,
+}
+
+impl Filesystem {
+    /// Open the filesystem on the block device or image at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, &FilesystemOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &FilesystemOptions) -> Result<Self> {
+        Self::open_devices(&[path], options)
+    }
+
+    /// Assemble a filesystem that spans all the block devices or images in `paths`. Devices
+    /// can be given in any order.
+    pub fn open_devices<P: AsRef<Path>>(paths: &[P], options: &FilesystemOptions) -> Result<Self> {
+        let mut warnings = Warnings::default();
+        let (devices, superblock) = Devices::open(paths, options.super_mirror, &mut warnings)?;
+
+        // Read chunk tree and root tree root, falling back to backup roots if necessary
+        let candidates = superblock::tree_roots_candidates(&superblock, options.backup_root)?;
+        let mut opened = None;
+        for roots in candidates {
+            debug!(
+                "trying tree roots from {} chunk_root={} root={}",
+                roots.source, roots.chunk_root, roots.root
+            );
+            match open_tree_roots(&devices, &superblock, &roots, &mut warnings) {
+                Ok(chunk_tree) => {
+                    opened = Some((roots, chunk_tree));
+                    break;
+                }
+                Err(e) => warnings.push(format!(
+                    "tree roots from {} unusable: {:#}",
+                    roots.source, e
+                )),
+            }
+        }
+        let (tree_roots, chunk_tree) = opened.ok_or_else(|| {
+            anyhow!(
+                "failed to read chunk tree and root tree root: {}",
+                warnings.as_slice().join("; ")
+            )
+        })?;
+
+        let missing_devices = devices.check_dev_items(&chunk_tree.dev_items)?;
+        for dev_item in &missing_devices {
+            warnings.push(format!(
+                "device devid={} uuid={} is missing",
+                dev_item.devid,
+                format_uuid(&dev_item.uuid)
+            ));
+        }
+
+        // The root tree root was read and checked along with the chunk tree
+        let root_tree_root: Rc<[u8]> = chunk_tree.root_tree_root.into();
+        let chunk_tree_cache = chunk_tree.chunk_tree_cache;
+        let fs_tree_root = find_fs_tree_root(tree_roots.root, |logical| {
+            if logical == tree_roots.root {
+                return Ok(root_tree_root.clone());
+            }
+            read_tree_block(
+                &devices,
+                &superblock,
+                &chunk_tree_cache,
+                logical,
+                &mut warnings,
+            )
+            .map(Rc::from)
+        })?;
+
+        let cache_size = options.block_cache_size.unwrap_or(DEFAULT_BLOCK_CACHE_SIZE);
+        let block_cache = BlockCache::new(cache_size, superblock.node_size as usize);
+
+        let shared = Shared {
+            devices,
+            superblock,
+            missing_devices,
+            chunk_tree_cache,
+            tree_roots,
+            warnings: RefCell::new(warnings),
+            block_cache,
+            subvolumes: RefCell::new(None),
+        };
+
+        Ok(Self {
+            shared: Rc::new(shared),
+            subvol: BTRFS_FS_TREE_OBJECTID,
+            fs_tree_root,
+            subvol_path: OsString::new(),
+        })
+    }
+
+    pub fn superblock(&self) -> &BtrfsSuperblock {
+        &self.shared.superblock
+    }
+
+    pub fn devices(&self) -> &Devices {
+        &self.shared.devices
+    }
+
+    /// DEV_ITEMs from the chunk tree of devices that were not given to `open_devices`
+    pub fn missing_devices(&self) -> &[BtrfsDevItem] {
+        &self.shared.missing_devices
+    }
+
+    /// Logical to physical address mapping built from the chunk tree
+    pub fn chunk_map(&self) -> &ChunkTreeCache {
+        &self.shared.chunk_tree_cache
+    }
+
+    /// Chunk tree and root tree roots in use. May come from a backup root slot.
+    pub fn tree_roots(&self) -> &TreeRoots {
+        &self.shared.tree_roots
+    }
+
+    /// Id of the subvolume files are read from
+    pub fn subvol_id(&self) -> u64 {
+        self.subvol
+    }
+
+    /// Logical address of the subvolume's fs tree root node
+    pub fn fs_tree_root(&self) -> u64 {
+        self.fs_tree_root
+    }
+
+    /// Problems that were worked around so far, while opening the filesystem or reading from it
+    pub fn warnings(&self) -> Vec<String> {
+        self.shared.warnings.borrow().as_slice().to_vec()
+    }
+
+    /// Fill `buf` with the bytes at logical address `logical`, reading from whichever copy is
+    /// available and rebuilding RAID5/6 data from parity if needed
+    pub fn read_logical(&self, logical: u64, buf: &mut [u8]) -> Result<()> {
+        read_logical(
+            &self.shared.devices,
+            &self.shared.chunk_tree_cache,
+            logical,
+            buf,
+            &mut self.shared.warnings.borrow_mut(),
+        )
+    }
+
+    /// Read and verify the tree block at logical address `logical`, falling back to other
+    /// copies if the first one is bad. Blocks are cached, so reading the same block again is
+    /// cheap.
+    pub fn read_tree_block(&self, logical: u64) -> Result<Rc<[u8]>> {
+        self.shared.block_cache.get_or_read(logical, || {
+            let node = read_tree_block(
+                &self.shared.devices,
+                &self.shared.superblock,
+                &self.shared.chunk_tree_cache,
+                logical,
+                &mut self.shared.warnings.borrow_mut(),
+            )?;
+
+            Ok(node.into())
+        })
+    }
+
+    /// Log a problem that was worked around, unless it was already raised
+    pub(crate) fn warn(&self, warning: String) {
+        self.shared.warnings.borrow_mut().push(warning);
+    }
+
+    /// Iterate over every file, directory, symlink and other entry in the fs tree. Subvolumes
+    /// mounted inside it are walked afterwards.
+    pub fn files(&self) -> Files {
+        Files {
+            fs: self.clone(),
+            stack: vec![(self.fs_tree_root, None)],
+            pending: VecDeque::new(),
+            subvols: VecDeque::new(),
+            visited_subvols: vec![self.subvol].into_iter().collect(),
+            dir_paths: HashMap::new(),
+        }
+    }
+
+    /// Iterate over every entry like `files`, but walk directories depth first through their
+    /// DIR_INDEX items, so each directory lists its entries in `readdir` order. Entries that
+    /// DIR_ITEM and DIR_INDEX items disagree on are reported in `warnings`.
+    pub fn files_in_readdir_order(&self) -> ReaddirFiles {
+        ReaddirFiles {
+            fs: self.clone(),
+            stack: Vec::new(),
+            expand: Some((
+                self.subvol,
+                BTRFS_FIRST_FREE_OBJECTID,
+                with_slash(&self.subvol_path),
+            )),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Find the inode number of the file or directory at `path`, which starts at the top
+    /// directory of this subvolume. Use `resolve` for paths that lead into other subvolumes.
+    pub fn lookup<P: AsRef<OsStr>>(&self, path: P) -> Result<u64> {
+        let (fs, inode) = self.resolve(&path)?;
+        if fs.subvol != self.subvol {
+            bail!(
+                "{}: is in subvolume id={}",
+                path.as_ref().to_string_lossy(),
+                fs.subvol
+            );
+        }
+
+        Ok(inode)
+    }
+
+    /// Find the file or directory at `path`, which starts at the top directory of this
+    /// subvolume, crossing into other subvolumes at their mount points. Returns the inode
+    /// number along with a handle on the subvolume holding it.
+    pub fn resolve<P: AsRef<OsStr>>(&self, path: P) -> Result<(Filesystem, u64)> {
+        let path = path.as_ref();
+        let mut fs = self.clone();
+        let mut inode = BTRFS_FIRST_FREE_OBJECTID;
+        let names = path.as_bytes().split(|&b| b == b'/');
+        for name in names.filter(|c| !c.is_empty() && *c != b".") {
+            let entry = fs
+                .find_dir_entry(inode, name)?
+                .ok_or_else(|| anyhow!("{}: no such file or directory", path.to_string_lossy()))?;
+            if let Some(subvol) = entry.subvol {
+                fs = fs.subvolume(subvol)?;
+            }
+            inode = entry.inode;
+        }
+
+        Ok((fs, inode))
+    }
+
+    /// Find entry `name` of directory `dir`
+    fn find_dir_entry(&self, dir: u64, name: &[u8]) -> Result<Option<DirEntry>> {
+        // DIR_ITEMs are keyed by name hash, names that collide share an item
+        let key = BtrfsKey {
+            objectid: dir,
+            ty: BTRFS_DIR_ITEM_KEY,
+            offset: tree::name_hash(name),
+        };
+        let data = match self.fs_tree_item(&key)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        for entry in tree::parse_dir_items(&data)? {
+            if entry.name == name {
+                return Ok(Some(DirEntry::new(&entry)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the INODE_ITEM of `inode`
+    pub fn inode_item(&self, inode: u64) -> Result<BtrfsInodeItem> {
+        let key = BtrfsKey {
+            objectid: inode,
+            ty: BTRFS_INODE_ITEM_KEY,
+            offset: 0,
+        };
+        let data = self
+            .fs_tree_item(&key)?
+            .ok_or_else(|| anyhow!("inode={} not found", inode))?;
+
+        BtrfsInodeItem::parse(&data)
+    }
+
+    /// Every name of `inode`, one for each hard link. The root directory has a single ref
+    /// named ".." that points to itself.
+    pub fn inode_refs(&self, inode: u64) -> Result<Vec<InodeRef>> {
+        let mut refs = Vec::new();
+        // The key offset is the parent directory
+        for (key, data) in self.fs_tree_items(inode, BTRFS_INODE_REF_KEY)? {
+            for (inode_ref, name) in tree::parse_inode_refs(&data)? {
+                refs.push(InodeRef {
+                    parent: key.offset,
+                    name: OsStr::from_bytes(name).to_os_string(),
+                    index: inode_ref.index,
+                });
+            }
+        }
+
+        // Links that don't fit in the INODE_REF item of their directory
+        if self.shared.superblock.incompat_flags & BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF != 0 {
+            for (_, data) in self.fs_tree_items(inode, BTRFS_INODE_EXTREF_KEY)? {
+                for (extref, name) in tree::parse_inode_extrefs(&data)? {
+                    refs.push(InodeRef {
+                        parent: extref.parent_objectid,
+                        name: OsStr::from_bytes(name).to_os_string(),
+                        index: extref.index,
+                    });
+                }
+            }
+        }
+
+        Ok(refs)
+    }
+
+    /// Absolute paths of every hard link of `inode`
+    pub fn paths(&self, inode: u64) -> Result<Vec<OsString>> {
+        let mut paths = Vec::new();
+        for inode_ref in self.inode_refs(inode)? {
+            if inode_ref.parent == inode {
+                let path = self.dir_path(inode)?;
+                paths.push(match path.len() {
+                    1 => path,
+                    len => OsStr::from_bytes(&path.as_bytes()[..len - 1]).to_os_string(),
+                });
+            } else {
+                let mut path = self.dir_path(inode_ref.parent)?;
+                path.push(&inode_ref.name);
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// List the entries of directory `dir`, in name hash order
+    pub fn read_dir(&self, dir: u64) -> Result<Vec<DirEntry>> {
+        self.dir_entries(dir, BTRFS_DIR_ITEM_KEY)
+    }
+
+    /// List the entries of directory `dir` in `readdir` order, which is the order they were
+    /// created in
+    pub fn read_dir_index(&self, dir: u64) -> Result<Vec<DirEntry>> {
+        self.dir_entries(dir, BTRFS_DIR_INDEX_KEY)
+    }
+
+    /// Decode the DIR_ITEM or DIR_INDEX items of `dir`
+    fn dir_entries(&self, dir: u64, ty: u8) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for (_, data) in self.fs_tree_items(dir, ty)? {
+            for entry in tree::parse_dir_items(&data)? {
+                entries.push(DirEntry::new(&entry)?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Warn about entries of `dir` that are only in its DIR_ITEMs or only in `index_entries`,
+    /// or that point to different inodes or types. Either index alone would make them look fine.
+    fn check_dir_index(&self, dir: u64, index_entries: &[DirEntry]) -> Result<()> {
+        for warning in dir_index_mismatches(dir, &self.read_dir(dir)?, index_entries) {
+            self.warn(warning);
+        }
+
+        Ok(())
+    }
+
+    /// Create a cursor over the fs tree, positioned before the first item with a key >= `key`
+    pub fn fs_tree_cursor(
+        &self,
+        key: &BtrfsKey,
+    ) -> Result<tree::Cursor<impl tree::ReadBlock + '_>> {
+        tree::Cursor::new(self.fs_tree_root, key, move |logical| {
+            self.read_tree_block(logical)
+        })
+    }
+
+    /// Collect the items of `objectid` with type `ty` from the fs tree, in key order
+    pub(crate) fn fs_tree_items(&self, objectid: u64, ty: u8) -> Result<Vec<(BtrfsKey, Vec<u8>)>> {
+        let min = BtrfsKey {
+            objectid,
+            ty,
+            offset: 0,
+        };
+        let max = BtrfsKey {
+            offset: u64::MAX,
+            ..min
+        };
+
+        tree::search_range(self.fs_tree_root, &min, &max, |logical| {
+            self.read_tree_block(logical)
+        })
+    }
+
+    /// Find the item with key `key` in the fs tree
+    pub(crate) fn fs_tree_item(&self, key: &BtrfsKey) -> Result<Option<Vec<u8>>> {
+        tree::search(self.fs_tree_root, key, |logical| {
+            self.read_tree_block(logical)
+        })
+    }
+
+    /// Compute the absolute path of directory inode `dir`, with a trailing '/'
+    pub(crate) fn dir_path(&self, dir: u64) -> Result<OsString> {
+        // Names from `dir` up to the top directory
+        let mut names = Vec::new();
+        let mut current_inode_nr = dir;
+        let mut visited = HashSet::new();
+        loop {
+            if !visited.insert(current_inode_nr) {
+                bail!("inode_ref loop detected at inode={}", current_inode_nr);
+            }
+
+            // Directories can't have hard links, so they only have one ref
+            let inode_ref = self
+                .inode_refs(current_inode_nr)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    anyhow!("Failed to find inode_ref for inode={}", current_inode_nr)
+                })?;
+
+            if inode_ref.parent == current_inode_nr {
+                // If parent inode # and current inode # match, that means ".." points to
+                // itself and we've hit the subvolume root.
+                break;
+            }
+
+            names.push(inode_ref.name);
+
+            current_inode_nr = inode_ref.parent;
+        }
+
+        let mut path_prefix = with_slash(&self.subvol_path);
+        for name in names.iter().rev() {
+            path_prefix.push(name);
+            path_prefix.push("/");
+        }
+
+        Ok(path_prefix)
+    }
+
+    /// Turn `entry` of the directory at `path_prefix` into a `FileEntry`
+    fn file_entry(&self, path_prefix: &OsStr, entry: DirEntry) -> FileEntry {
+        let mut path = path_prefix.to_os_string();
+        path.push(&entry.name);
+
+        FileEntry {
+            path,
+            subvol: entry.subvol.unwrap_or(self.subvol),
+            inode: entry.inode,
+            ty: entry.ty,
+        }
+    }
+
+    /// Handle on subvolume `subvol`, whose fs tree root node is at `fs_tree_root` and whose top
+    /// directory is at `subvol_path`
+    pub(crate) fn subvolume_handle(
+        &self,
+        subvol: u64,
+        fs_tree_root: u64,
+        subvol_path: OsString,
+    ) -> Filesystem {
+        Filesystem {
+            shared: self.shared.clone(),
+            subvol,
+            fs_tree_root,
+            subvol_path,
+        }
+    }
+
+    /// Subvolumes found so far, see `Filesystem::subvolumes`
+    pub(crate) fn subvolume_cache(&self) -> &RefCell<Option<Vec<Subvolume>>> {
+        &self.shared.subvolumes
+    }
+
+    /// Collect the items with keys from `min` up to and including `max` from the root tree
+    pub(crate) fn root_tree_items(
+        &self,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Vec<(BtrfsKey, Vec<u8>)>> {
+        tree::search_range(self.shared.tree_roots.root, min, max, |logical| {
+            self.read_tree_block(logical)
+        })
+    }
+}
+
+/// An entry of a directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: OsString,
+    /// Inode number the entry points to
+    pub inode: u64,
+    pub ty: FileType,
+    /// Set if the entry is the mount point of another subvolume. `inode` is then the top
+    /// directory of that subvolume.
+    pub subvol: Option<u64>,
+}
+
+impl DirEntry {
+    fn new(entry: &tree::DirItemEntry) -> Result<Self> {
+        let location = entry.dir_item.location;
+        let (inode, subvol) = if location.ty == BTRFS_ROOT_ITEM_KEY {
+            (BTRFS_FIRST_FREE_OBJECTID, Some(location.objectid))
+        } else {
+            (location.objectid, None)
+        };
+
+        Ok(Self {
+            name: OsStr::from_bytes(entry.name).to_os_string(),
+            inode,
+            ty: FileType::from_raw(entry.dir_item.ty),
+            subvol,
+        })
+    }
+}
+
+/// One name of an inode: entry `name` of directory `parent`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeRef {
+    /// Inode number of the directory holding the entry
+    pub parent: u64,
+    pub name: OsString,
+    /// Position of the entry in `readdir` order, the offset of its DIR_INDEX key
+    pub index: u64,
+}
+
+/// Iterator over the entries of a `Filesystem`, see `Filesystem::files`
+pub struct Files {
+    /// Subvolume being walked
+    fs: Filesystem,
+    /// Logical addresses of fs tree blocks still to visit, last one first, along with the level
+    /// of the node that points to them
+    stack: Vec<(u64, Option<u8>)>,
+    /// Files found in the last visited leaf
+    pending: VecDeque<FileEntry>,
+    /// Subvolumes whose mount points were found, to walk once this one is done
+    subvols: VecDeque<u64>,
+    visited_subvols: HashSet<u64>,
+    /// Paths of the directories resolved so far by subvolume and inode, with a trailing '/'.
+    /// A directory's entries can span many DIR_ITEMs and leaves.
+    dir_paths: HashMap<(u64, u64), OsString>,
+}
+
+impl Files {
+    /// Visit the next tree block on the stack
+    fn visit_next(&mut self, logical: u64, parent_level: Option<u8>) -> Result<()> {
+        let node = self.fs.read_tree_block(logical)?;
+        if let Some(parent_level) = parent_level {
+            tree::check_child_level(parent_level, &node)?;
+        }
+        let header = tree::parse_btrfs_header(&node)?;
+
+        // Leaf node
+        if header.level == 0 {
+            for file in self.leaf_files(&node)? {
+                if file.subvol != self.fs.subvol && self.visited_subvols.insert(file.subvol) {
+                    self.subvols.push_back(file.subvol);
+                }
+                self.pending.push_back(file);
+            }
+        } else {
+            let ptrs = tree::parse_btrfs_node(&node)?;
+            self.stack.extend(
+                ptrs.iter()
+                    .rev()
+                    .map(|ptr| (ptr.blockptr, Some(header.level))),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Collect the entries referenced by DIR_ITEMs in leaf `node`
+    fn leaf_files(&mut self, node: &[u8]) -> Result<Vec<FileEntry>> {
+        let mut files = Vec::new();
+
+        let items = tree::parse_btrfs_leaf(node)?;
+        for item in items {
+            if item.key.ty != BTRFS_DIR_ITEM_KEY {
+                continue;
+            }
+
+            // `item.key.objectid` is parent inode number
+            let path_prefix = self.dir_path(item.key.objectid)?;
+
+            for entry in tree::parse_dir_items(tree::item_data(node, &item)?)? {
+                files.push(self.fs.file_entry(&path_prefix, DirEntry::new(&entry)?));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Path of directory `dir` of the subvolume being walked, see `Filesystem::dir_path`
+    fn dir_path(&mut self, dir: u64) -> Result<OsString> {
+        let key = (self.fs.subvol, dir);
+        if let Some(path) = self.dir_paths.get(&key) {
+            return Ok(path.clone());
+        }
+
+        let path = self.fs.dir_path(dir)?;
+        self.dir_paths.insert(key, path.clone());
+
+        Ok(path)
+    }
+}
+
+impl Iterator for Files {
+    type Item = Result<FileEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(file) = self.pending.pop_front() {
+                return Some(Ok(file));
+            }
+
+            if self.stack.is_empty() {
+                let subvol = self.subvols.pop_front()?;
+                debug!("walking subvolume id={}", subvol);
+                match self.fs.subvolume(subvol) {
+                    Ok(fs) => self.fs = fs,
+                    Err(e) => return Some(Err(e)),
+                }
+                self.stack.push((self.fs.fs_tree_root, None));
+            }
+
+            let (logical, parent_level) = self.stack.pop()?;
+            if let Err(e) = self.visit_next(logical, parent_level) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Iterator over the entries of a `Filesystem` in `readdir` order, see
+/// `Filesystem::files_in_readdir_order`
+pub struct ReaddirFiles {
+    /// Subvolume the last expanded directory is in
+    fs: Filesystem,
+    /// Entries still to return, next one last
+    stack: Vec<FileEntry>,
+    /// Directory returned last, as subvolume, inode and path with a trailing '/'. Its entries
+    /// go on the stack before anything else is returned.
+    expand: Option<(u64, u64, OsString)>,
+    /// Directories already expanded, a directory reachable twice means the fs tree is broken
+    visited: HashSet<(u64, u64)>,
+}
+
+impl ReaddirFiles {
+    fn expand_dir(&mut self, subvol: u64, dir: u64, path_prefix: &OsStr) -> Result<()> {
+        if !self.visited.insert((subvol, dir)) {
+            self.fs.warn(format!(
+                "dir inode={} of subvolume id={} is reachable from more than one path, not \
+                 listing it again at {}",
+                dir,
+                subvol,
+                path_prefix.to_string_lossy()
+            ));
+            return Ok(());
+        }
+
+        if subvol != self.fs.subvol {
+            self.fs = self.fs.subvolume(subvol)?;
+        }
+        let entries = self.fs.read_dir_index(dir)?;
+        self.fs.check_dir_index(dir, &entries)?;
+        for entry in entries.into_iter().rev() {
+            self.stack.push(self.fs.file_entry(path_prefix, entry));
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for ReaddirFiles {
+    type Item = Result<FileEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((subvol, dir, path_prefix)) = self.expand.take() {
+            if let Err(e) = self.expand_dir(subvol, dir, &path_prefix) {
+                return Some(Err(e));
+            }
+        }
+
+        let file = self.stack.pop()?;
+        if file.ty == FileType::Directory {
+            self.expand = Some((file.subvol, file.inode, with_slash(&file.path)));
+        }
+
+        Some(Ok(file))
+    }
+}
+
+/// Describe the entries of directory `dir` that are only in `item_entries`, from its DIR_ITEMs,
+/// or only in `index_entries`, from its DIR_INDEX items, or that the two disagree on
+fn dir_index_mismatches(
+    dir: u64,
+    item_entries: &[DirEntry],
+    index_entries: &[DirEntry],
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut by_name: HashMap<&OsStr, &DirEntry> = index_entries
+        .iter()
+        .map(|entry| (entry.name.as_os_str(), entry))
+        .collect();
+    for entry in item_entries {
+        let name = entry.name.to_string_lossy();
+        match by_name.remove(entry.name.as_os_str()) {
+            None => warnings.push(format!(
+                "dir inode={}: entry '{}' has a DIR_ITEM but no DIR_INDEX",
+                dir, name
+            )),
+            Some(index) if (index.inode, index.ty) != (entry.inode, entry.ty) => {
+                warnings.push(format!(
+                    "dir inode={}: entry '{}' points to inode={} type={} in its DIR_ITEM but \
+                     inode={} type={} in its DIR_INDEX",
+                    dir, name, entry.inode, entry.ty, index.inode, index.ty
+                ))
+            }
+            Some(_) => (),
+        }
+    }
+    // Keep the warnings in readdir order
+    for entry in index_entries {
+        if by_name.contains_key(entry.name.as_os_str()) {
+            warnings.push(format!(
+                "dir inode={}: entry '{}' has a DIR_INDEX but no DIR_ITEM",
+                dir,
+                entry.name.to_string_lossy()
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// `path` followed by a '/'
+fn with_slash(path: &OsStr) -> OsString {
+    let mut path = path.to_os_string();
+    path.push("/");
+    path
+}
+
+/// Make sure a tree block read from `physical` is intact and really is the block at `logical`
+fn check_tree_block(
+    superblock: &BtrfsSuperblock,
+    node: &[u8],
+    logical: u64,
+    physical: u64,
+) -> Result<()> {
+    let csum_type = CsumType::from_raw(superblock.csum_type)?;
+    csum::verify(csum_type, node, "tree block", logical, physical)?;
+
+    let header = tree::parse_btrfs_header(node)?;
+    if header.bytenr != logical {
+        bail!(
+            "tree block at logical={} claims to be at bytenr={}",
+            logical,
+            header.bytenr
+        );
+    }
+
+    let fsid = if superblock.incompat_flags & BTRFS_FEATURE_INCOMPAT_METADATA_UUID != 0 {
+        superblock.metadata_uuid
+    } else {
+        superblock.fsid
+    };
+    if header.fsid != fsid {
+        bail!(
+            "tree block at logical={} belongs to filesystem {}",
+            logical,
+            format_uuid(&header.fsid)
+        );
+    }
+
+    Ok(())
+}
+
+/// Read the tree block at `logical` and validate it, trying every copy until one is good. If
+/// copies had to be skipped, each one gets a warning and so does the copy used instead.
+fn read_tree_block(
+    devices: &Devices,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    logical: u64,
+    warnings: &mut Warnings,
+) -> Result<Vec<u8>> {
+    let mapping = cache
+        .map(logical)
+        .ok_or_else(|| anyhow!("tree block at logical={} not mapped", logical))?;
+    let mut node = vec![0; superblock.node_size as usize];
+    if (mapping.len as usize) < node.len() {
+        bail!(
+            "tree block at logical={} crosses a stripe boundary",
+            logical
+        );
+    }
+
+    let mut failures = Vec::new();
+    for copy in 0..mapping.num_copies() {
+        let physical = mapping
+            .mirrors
+            .get(copy)
+            .map_or(0, |(_, physical)| *physical);
+        let ret = read_copy(devices, &mapping, copy, &mut node)
+            .and_then(|_| check_tree_block(superblock, &node, logical, physical));
+        match ret {
+            Ok(()) => {
+                let header = tree::parse_btrfs_header(&node)?;
+                let tree = tree::tree_name(header.owner);
+                debug!(
+                    "tree={} level={} bytenr={} nritems={} {}",
+                    tree,
+                    header.level,
+                    logical,
+                    header.nritems,
+                    copy_fields(&mapping, copy)
+                );
+                // Only the good copy can be trusted to say which tree the block is in
+                for (bad_copy, e) in &failures {
+                    warnings.push(format!(
+                        "bad tree block copy: tree={} bytenr={} {} error={:?}",
+                        tree,
+                        logical,
+                        copy_fields(&mapping, *bad_copy),
+                        e
+                    ));
+                }
+                if !failures.is_empty() {
+                    warnings.push(format!(
+                        "using tree block copy: tree={} bytenr={} {}",
+                        tree,
+                        logical,
+                        copy_fields(&mapping, copy)
+                    ));
+                }
+                return Ok(node);
+            }
+            Err(e) => failures.push((copy, format!("{:#}", e))),
+        }
+    }
+
+    let failures: Vec<_> = failures
+        .iter()
+        .map(|(copy, e)| format!("{}: {}", copy_fields(&mapping, *copy), e))
+        .collect();
+    bail!(
+        "no good copy of tree block at logical={}: {}",
+        logical,
+        failures.join("; ")
+    )
+}
+
+/// Log fields naming copy `copy` of `mapping`
+fn copy_fields(mapping: &StripeMapping, copy: usize) -> String {
+    match mapping.mirrors.get(copy) {
+        Some((devid, physical)) => format!("copy={} devid={} physical={}", copy, devid, physical),
+        None => format!("copy={} rebuilt=parity", copy),
+    }
+}
+
+/// Read `buf.len()` bytes at `logical`, one stripe at a time
+fn read_logical(
+    devices: &Devices,
+    cache: &ChunkTreeCache,
+    logical: u64,
+    buf: &mut [u8],
+    warnings: &mut Warnings,
+) -> Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = logical + done as u64;
+        let mapping = cache
+            .map(pos)
+            .ok_or_else(|| anyhow!("logical={} not mapped", pos))?;
+        let len = (mapping.len as usize).min(buf.len() - done);
+        read_stripe(devices, &mapping, pos, &mut buf[done..done + len], warnings)
+            .with_context(|| format!("failed to read {} bytes at logical={}", len, pos))?;
+        done += len;
+    }
+
+    Ok(())
+}
+
+/// Read from the first copy in `mapping`, which maps `logical`, that works. If copies had to
+/// be skipped, each one gets a warning.
+fn read_stripe(
+    devices: &Devices,
+    mapping: &StripeMapping,
+    logical: u64,
+    buf: &mut [u8],
+    warnings: &mut Warnings,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for copy in 0..mapping.num_copies() {
+        match read_copy(devices, mapping, copy, buf) {
+            Ok(()) => {
+                for (bad_copy, e) in errors {
+                    warnings.push(format!(
+                        "bad data copy: logical={} {} error={:?}",
+                        logical,
+                        copy_fields(mapping, bad_copy),
+                        e
+                    ));
+                }
+                return Ok(());
+            }
+            Err(e) => errors.push((copy, format!("{:#}", e))),
+        }
+    }
+
+    let errors: Vec<_> = errors.into_iter().map(|(_, e)| e).collect();
+    bail!("{}", errors.join("; "))
+}
+
+/// Read copy `copy` of `mapping`: one of the mirrors, or for RAID5/6 the data rebuilt from the
+/// rest of the full stripe
+fn read_copy(
+    devices: &Devices,
+    mapping: &StripeMapping,
+    copy: usize,
+    buf: &mut [u8],
+) -> Result<()> {
+    if let Some((devid, physical)) = mapping.mirrors.get(copy) {
+        return devices.read_exact_at(*devid, buf, *physical);
+    }
+
+    let parity = match &mapping.parity {
+        Some(parity) if copy == mapping.mirrors.len() => parity,
+        _ => bail!("copy {} does not exist", copy),
+    };
+
+    let read = |(devid, physical): (u64, u64)| {
+        let mut stripe = vec![0; buf.len()];
+        devices
+            .read_exact_at(devid, &mut stripe, physical)
+            .ok()
+            .map(|_| stripe)
+    };
+    let mut data: Vec<_> = parity.data.iter().map(|addr| read(*addr)).collect();
+    data[parity.data_index] = None;
+    let p = read(parity.p);
+    let q = parity.q.and_then(read);
+    let rebuilt = raid::reconstruct(parity.data_index, &data, p.as_deref(), q.as_deref())
+        .context("parity rebuild failed")?;
+    buf.copy_from_slice(&rebuilt);
+
+    Ok(())
+}
+
+/// Decode a chunk item followed by all of its stripes
+fn read_chunk_item(reader: &mut Reader) -> Result<(BtrfsChunk, ChunkTreeValue)> {
+    let chunk = BtrfsChunk::read(reader)?;
+    if chunk.num_stripes == 0 {
+        bail!("num_stripes cannot be 0");
+    }
+
+    let mut stripes = vec![chunk.stripe];
+    for _ in 1..chunk.num_stripes {
+        stripes.push(BtrfsStripe::read(reader)?);
+    }
+    let value = ChunkTreeValue::new(&chunk, &stripes)?;
+
+    Ok((chunk, value))
+}
+
+fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache> {
+    let array_size = superblock.sys_chunk_array_size as usize;
+    if array_size > superblock.sys_chunk_array.len() {
+        bail!("sys_chunk_array_size={} is too large", array_size);
+    }
+
+    let mut reader = Reader::new(&superblock.sys_chunk_array[..array_size]);
+    let mut chunk_tree_cache = ChunkTreeCache::default();
+
+    while reader.remaining() > 0 {
+        let key = BtrfsKey::read(&mut reader)?;
+        if key.ty != BTRFS_CHUNK_ITEM_KEY {
+            bail!(
+                "unknown item type={} in sys_array at offset={}",
+                key.ty,
+                reader.offset() - BtrfsKey::SIZE
+            );
+        }
+
+        let (chunk, value) = read_chunk_item(&mut reader)?;
+
+        chunk_tree_cache.insert(
+            ChunkTreeKey {
+                start: key.offset,
+                size: chunk.length,
+            },
+            value,
+        )?;
+    }
+
+    Ok(chunk_tree_cache)
+}
+
+/// Make sure a tree root is from the transaction that referenced it. A crash mid-transaction
+/// can leave a block that passes its checksum but belongs to a different generation.
+fn check_generation(node: &[u8], generation: u64) -> Result<()> {
+    let header = tree::parse_btrfs_header(node)?;
+    if header.generation != generation {
+        bail!(
+            "tree root at bytenr={} has generation={}, expected generation={}",
+            header.bytenr,
+            header.generation,
+            generation
+        );
+    }
+
+    Ok(())
+}
+
+/// Read the root node of a tree whose root is at `logical` and was committed in `generation`
+fn read_tree_root(
+    devices: &Devices,
+    superblock: &BtrfsSuperblock,
+    logical: u64,
+    generation: u64,
+    cache: &ChunkTreeCache,
+    warnings: &mut Warnings,
+) -> Result<Vec<u8>> {
+    let root = read_tree_block(devices, superblock, cache, logical, warnings)?;
+    check_generation(&root, generation)?;
+
+    Ok(root)
+}
+
+fn read_chunk_tree(
+    devices: &Devices,
+    root: &[u8],
+    chunk_tree_cache: &mut ChunkTreeCache,
+    dev_items: &mut Vec<BtrfsDevItem>,
+    superblock: &BtrfsSuperblock,
+    warnings: &mut Warnings,
+) -> Result<()> {
+    let header = tree::parse_btrfs_header(root)?;
+
+    // Level 0 is leaf node, !0 is internal node
+    if header.level == 0 {
+        let items = tree::parse_btrfs_leaf(root)?;
+        for item in items {
+            if item.key.ty == BTRFS_DEV_ITEM_KEY {
+                dev_items.push(BtrfsDevItem::parse(tree::item_data(root, &item)?)?);
+                continue;
+            }
+
+            if item.key.ty != BTRFS_CHUNK_ITEM_KEY {
+                continue;
+            }
+
+            let mut reader = Reader::new(tree::item_data(root, &item)?);
+            let (chunk, value) = read_chunk_item(&mut reader)?;
+
+            chunk_tree_cache.insert(
+                ChunkTreeKey {
+                    start: item.key.offset,
+                    size: chunk.length,
+                },
+                value,
+            )?;
+        }
+    } else {
+        let ptrs = tree::parse_btrfs_node(root)?;
+        for ptr in ptrs {
+            let node = read_tree_block(
+                devices,
+                superblock,
+                chunk_tree_cache,
+                ptr.blockptr,
+                warnings,
+            )?;
+            tree::check_child_level(header.level, &node)?;
+            read_chunk_tree(
+                devices,
+                &node,
+                chunk_tree_cache,
+                dev_items,
+                superblock,
+                warnings,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Everything loaded from a set of `TreeRoots`
+struct OpenedTreeRoots {
+    chunk_tree_cache: ChunkTreeCache,
+    /// DEV_ITEMs of every device in the filesystem
+    dev_items: Vec<BtrfsDevItem>,
+    root_tree_root: Vec<u8>,
+}
+
+/// Load the full chunk tree and the root tree root referenced by `roots`
+fn open_tree_roots(
+    devices: &Devices,
+    superblock: &BtrfsSuperblock,
+    roots: &TreeRoots,
+    warnings: &mut Warnings,
+) -> Result<OpenedTreeRoots> {
+    // Bootstrap chunk tree
+    let mut chunk_tree_cache = bootstrap_chunk_tree(superblock)?;
+
+    // Read root chunk tree node
+    let chunk_root = read_tree_root(
+        devices,
+        superblock,
+        roots.chunk_root,
+        roots.chunk_root_generation,
+        &chunk_tree_cache,
+        warnings,
+    )?;
+
+    // Read rest of chunk tree
+    let mut dev_items = Vec::new();
+    read_chunk_tree(
+        devices,
+        &chunk_root,
+        &mut chunk_tree_cache,
+        &mut dev_items,
+        superblock,
+        warnings,
+    )?;
+
+    // Read root tree root node
+    let root_tree_root = read_tree_root(
+        devices,
+        superblock,
+        roots.root,
+        roots.root_generation,
+        &chunk_tree_cache,
+        warnings,
+    )?;
+
+    Ok(OpenedTreeRoots {
+        chunk_tree_cache,
+        dev_items,
+        root_tree_root,
+    })
+}
+
+/// Find the logical address of the fs tree root node in the root tree rooted at `root`. The
+/// ROOT_ITEMs of a tree only differ by key offset and the one with the highest offset is
+/// current, which may be in any leaf.
+fn find_fs_tree_root<F: tree::ReadBlock>(root: u64, read_block: F) -> Result<u64> {
+    // Position after the last possible ROOT_ITEM of the fs tree and step back
+    let after = BtrfsKey {
+        objectid: BTRFS_FS_TREE_OBJECTID,
+        ty: BTRFS_ROOT_ITEM_KEY + 1,
+        offset: 0,
+    };
+    let mut cursor = tree::Cursor::new(root, &after, read_block)?;
+    match cursor.prev().transpose()? {
+        Some((key, data))
+            if key.objectid == BTRFS_FS_TREE_OBJECTID && key.ty == BTRFS_ROOT_ITEM_KEY =>
+        {
+            Ok(subvolume::parse_root_item(&data)?.bytenr)
+        }
+        _ => bail!("Failed to find root tree item for fs tree root"),
+    }
+}
+
+/// Write an image of a single device filesystem whose fs tree holds `fs_items` to a temporary
+/// file named after `name`. Logical addresses are physical ones and every tree is one leaf.
+#[cfg(test)]
+pub(crate) fn test_image(name: &str, fs_items: &[(BtrfsKey, Vec<u8>)]) -> std::path::PathBuf {
+    const FSID: [u8; BTRFS_FSID_SIZE] = [1; BTRFS_FSID_SIZE];
+    const DEV_UUID: [u8; BTRFS_UUID_SIZE] = [2; BTRFS_UUID_SIZE];
+    const CHUNK_ROOT: u64 = 0x20000;
+    const ROOT: u64 = 0x21000;
+    const FS_ROOT: u64 = 0x22000;
+    let put = |buf: &mut [u8], offset: usize, value: u64| {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes())
+    };
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let leaf = |bytenr, owner, items: &[(BtrfsKey, Vec<u8>)]| {
+        let mut leaf = tree::test_leaf(bytenr, items);
+        leaf[32..48].copy_from_slice(&FSID);
+        put(&mut leaf, 80, 1); // generation
+        put(&mut leaf, 88, owner);
+        let csum = CsumType::Crc32c.compute(&leaf[BTRFS_CSUM_SIZE..]);
+        leaf[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+        leaf
+    };
+
+    let mut dev_item = vec![0; BtrfsDevItem::SIZE];
+    put(&mut dev_item, 0, 1); // devid
+    dev_item[66..82].copy_from_slice(&DEV_UUID);
+    dev_item[82..98].copy_from_slice(&FSID);
+
+    // The first MiB, mapped to the same physical range of devid 1
+    let mut chunk = vec![0; BtrfsChunk::SIZE];
+    put(&mut chunk, 0, 1 << 20); // length
+    put(&mut chunk, 16, 0x10000); // stripe_len
+    let ty = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_METADATA;
+    put(&mut chunk, 24, ty);
+    chunk[44] = 1; // num_stripes
+    put(&mut chunk, 48, 1); // devid
+    chunk[64..80].copy_from_slice(&DEV_UUID);
+    let chunk_key = key(256, BTRFS_CHUNK_ITEM_KEY, 0);
+
+    let mut root_item = vec![0; BtrfsRootItem::SIZE];
+    put(&mut root_item, 160, 1); // generation
+    put(&mut root_item, 168, 256); // root_dirid
+    put(&mut root_item, 176, FS_ROOT);
+
+    let mut superblock = vec![0; 4096];
+    superblock[32..48].copy_from_slice(&FSID);
+    put(&mut superblock, 48, 0x10000); // bytenr
+    superblock[64..72].copy_from_slice(b"_BHRfS_M");
+    put(&mut superblock, 72, 1); // generation
+    put(&mut superblock, 80, ROOT);
+    put(&mut superblock, 88, CHUNK_ROOT);
+    put(&mut superblock, 136, 1); // num_devices
+    superblock[144..148].copy_from_slice(&4096u32.to_le_bytes()); // sector_size
+    superblock[148..152].copy_from_slice(&1024u32.to_le_bytes()); // node_size
+    let sys_chunk_array_size = (BtrfsKey::SIZE + chunk.len()) as u32;
+    superblock[160..164].copy_from_slice(&sys_chunk_array_size.to_le_bytes());
+    put(&mut superblock, 164, 1); // chunk_root_generation
+    superblock[201..299].copy_from_slice(&dev_item);
+    superblock[811..819].copy_from_slice(&chunk_key.objectid.to_le_bytes());
+    superblock[819] = chunk_key.ty;
+    superblock[820..828].copy_from_slice(&chunk_key.offset.to_le_bytes());
+    superblock[828..828 + chunk.len()].copy_from_slice(&chunk);
+    let csum = CsumType::Crc32c.compute(&superblock[BTRFS_CSUM_SIZE..]);
+    superblock[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+
+    let chunk_items = [
+        (key(1, BTRFS_DEV_ITEM_KEY, 1), dev_item),
+        (chunk_key, chunk),
+    ];
+    let root_items = [(
+        key(BTRFS_FS_TREE_OBJECTID, BTRFS_ROOT_ITEM_KEY, 0),
+        root_item,
+    )];
+    let mut image = vec![0; 0x30000];
+    for (offset, block) in [
+        (0x10000, superblock),
+        (CHUNK_ROOT, leaf(CHUNK_ROOT, 3, &chunk_items)),
+        (ROOT, leaf(ROOT, 1, &root_items)),
+        (FS_ROOT, leaf(FS_ROOT, BTRFS_FS_TREE_OBJECTID, fs_items)),
+    ] {
+        image[offset as usize..offset as usize + block.len()].copy_from_slice(&block);
+    }
+
+    let path = std::env::temp_dir().join(format!("btrfs-walk-{}-{}.img", name, std::process::id()));
+    std::fs::write(&path, image).unwrap();
+    path
+}
+
+/// An INODE_ITEM with `mode` and `size`
+#[cfg(test)]
+pub(crate) fn test_inode_item(mode: u32, size: u64) -> Vec<u8> {
+    let mut item = vec![0; BtrfsInodeItem::SIZE];
+    item[16..24].copy_from_slice(&size.to_le_bytes());
+    item[40..44].copy_from_slice(&1u32.to_le_bytes()); // nlink
+    item[52..56].copy_from_slice(&mode.to_le_bytes());
+    item
+}
+
+#[cfg(test)]
+fn dir_entry(name: &str, inode: u64, ty: FileType) -> DirEntry {
+    DirEntry {
+        name: name.into(),
+        inode,
+        ty,
+        subvol: None,
+    }
+}
+
+#[test]
+fn test_dir_index_mismatches() {
+    let items = [
+        dir_entry("same", 257, FileType::Regular),
+        dir_entry("no_index", 258, FileType::Regular),
+        dir_entry("other_inode", 259, FileType::Regular),
+        dir_entry("other_type", 260, FileType::Regular),
+    ];
+    let index = [
+        dir_entry("other_type", 260, FileType::Directory),
+        dir_entry("no_item", 261, FileType::Regular),
+        dir_entry("same", 257, FileType::Regular),
+        dir_entry("other_inode", 262, FileType::Regular),
+    ];
+    assert_eq!(
+        dir_index_mismatches(256, &items, &index),
+        [
+            "dir inode=256: entry 'no_index' has a DIR_ITEM but no DIR_INDEX",
+            "dir inode=256: entry 'other_inode' points to inode=259 type=f in its DIR_ITEM but \
+             inode=262 type=f in its DIR_INDEX",
+            "dir inode=256: entry 'other_type' points to inode=260 type=f in its DIR_ITEM but \
+             inode=260 type=d in its DIR_INDEX",
+            "dir inode=256: entry 'no_item' has a DIR_INDEX but no DIR_ITEM",
+        ]
+    );
+
+    // Both indexes agree, in any order
+    assert!(dir_index_mismatches(256, &items[..1], &index[2..3]).is_empty());
+}
+
+#[test]
+fn test_block_cache() {
+    let reads = RefCell::new(Vec::new());
+    let read = |logical: u64| {
+        reads.borrow_mut().push(logical);
+        Ok(Rc::from(vec![logical as u8; 16]))
+    };
+
+    // Room for 3 blocks of 16 bytes
+    let cache = BlockCache::new(3 * 16 + 8, 16);
+    for logical in 1..=5 {
+        assert_eq!(
+            cache.get_or_read(logical, || read(logical)).unwrap()[0],
+            logical as u8
+        );
+        assert!(cache.len() <= 3);
+    }
+    // The second read of a cached block does not hit the device
+    assert_eq!(cache.get_or_read(5, || read(5)).unwrap()[0], 5);
+    assert_eq!(*reads.borrow(), [1, 2, 3, 4, 5]);
+    // Least recently used blocks were dropped
+    cache.get_or_read(1, || read(1)).unwrap();
+    assert_eq!(*reads.borrow(), [1, 2, 3, 4, 5, 1]);
+    assert_eq!(cache.len(), 3);
+
+    // Failed reads are not cached
+    assert!(cache.get_or_read(6, || bail!("bad block")).is_err());
+    cache.get_or_read(6, || read(6)).unwrap();
+    assert_eq!(reads.borrow().last(), Some(&6));
+
+    // Size 0, or less than a block, disables caching
+    for size in [0, 15] {
+        let cache = BlockCache::new(size, 16);
+        reads.borrow_mut().clear();
+        cache.get_or_read(1, || read(1)).unwrap();
+        cache.get_or_read(1, || read(1)).unwrap();
+        assert_eq!(*reads.borrow(), [1, 1]);
+        assert_eq!(cache.len(), 0);
+    }
+}
+
+#[test]
+fn test_find_fs_tree_root() {
+    use tree::{test_key, test_leaf, test_node};
+
+    let key = |objectid, ty, offset| BtrfsKey {
+        offset,
+        ..test_key(objectid, ty)
+    };
+    // Only `bytenr` matters, shorter root items are padded with zeros
+    let root_item = |bytenr: u64| {
+        let mut data = vec![0; 184];
+        data[176..].copy_from_slice(&bytenr.to_le_bytes());
+        data
+    };
+    let mut blocks = HashMap::new();
+    blocks.insert(
+        8192,
+        test_leaf(
+            8192,
+            &[
+                (key(2, BTRFS_ROOT_ITEM_KEY, 0), root_item(100)),
+                (key(5, BTRFS_ROOT_ITEM_KEY, 0), root_item(200)),
+                (key(5, BTRFS_ROOT_ITEM_KEY, 9), root_item(300)),
+            ],
+        ),
+    );
+    blocks.insert(
+        12288,
+        test_leaf(
+            12288,
+            &[
+                (key(5, BTRFS_ROOT_REF_KEY, 256), vec![0; 18]),
+                (key(256, BTRFS_ROOT_ITEM_KEY, 0), root_item(400)),
+            ],
+        ),
+    );
+    let find = |blocks: &HashMap<u64, Vec<u8>>| {
+        find_fs_tree_root(4096, |bytenr| Ok(blocks[&bytenr].clone().into()))
+    };
+
+    // The search lands at the end of the first leaf
+    let first_key = key(2, BTRFS_ROOT_ITEM_KEY, 0);
+    let second_key = key(5, BTRFS_ROOT_REF_KEY, 256);
+    blocks.insert(
+        4096,
+        test_node(4096, 1, &[(first_key, 8192), (second_key, 12288)]),
+    );
+    assert_eq!(find(&blocks).unwrap(), 300);
+
+    // The root node's key for the second leaf is below its first item, so the search lands at
+    // the start of the second leaf and has to step back into the first
+    let low_key = key(5, BTRFS_ROOT_ITEM_KEY + 1, 0);
+    blocks.insert(
+        4096,
+        test_node(4096, 1, &[(first_key, 8192), (low_key, 12288)]),
+    );
+    assert_eq!(find(&blocks).unwrap(), 300);
+
+    // Two levels of internal nodes
+    blocks.insert(16384, test_node(16384, 2, &[(first_key, 4096)]));
+    assert_eq!(
+        find_fs_tree_root(16384, |bytenr| Ok(blocks[&bytenr].clone().into())).unwrap(),
+        300
+    );
+
+    // No ROOT_ITEM for the fs tree
+    blocks.insert(
+        8192,
+        test_leaf(8192, &[(key(2, BTRFS_ROOT_ITEM_KEY, 0), root_item(100))]),
+    );
+    assert!(find(&blocks).is_err());
+}
+
+#[test]
+fn test_warnings() {
+    let mut warnings = Warnings::default();
+    for warning in ["bad copy 0", "missing device", "bad copy 0"] {
+        warnings.push(warning.to_string());
+    }
+    assert_eq!(warnings.as_slice(), ["bad copy 0", "missing device"]);
+}
+
+#[test]
+fn test_non_utf8_names() {
+    let name = b"caf\xff";
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let inode_ref = |index: u64, name: &[u8]| {
+        let mut data = index.to_le_bytes().to_vec();
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(name);
+        data
+    };
+    let mut dir_item = vec![0; BtrfsDirItem::SIZE];
+    dir_item[..8].copy_from_slice(&257u64.to_le_bytes());
+    dir_item[8] = BTRFS_INODE_ITEM_KEY;
+    dir_item[27..29].copy_from_slice(&(name.len() as u16).to_le_bytes());
+    dir_item[29] = BTRFS_FT_REG_FILE;
+    dir_item.extend_from_slice(name);
+    let path = test_image(
+        "non-utf8-names",
+        &[
+            (
+                key(256, BTRFS_INODE_ITEM_KEY, 0),
+                test_inode_item(0o40755, 8),
+            ),
+            (key(256, BTRFS_INODE_REF_KEY, 256), inode_ref(0, b"..")),
+            (
+                key(256, BTRFS_DIR_ITEM_KEY, tree::name_hash(name)),
+                dir_item.clone(),
+            ),
+            (key(256, BTRFS_DIR_INDEX_KEY, 2), dir_item),
+            (
+                key(257, BTRFS_INODE_ITEM_KEY, 0),
+                test_inode_item(0o100644, 0),
+            ),
+            (key(257, BTRFS_INODE_REF_KEY, 256), inode_ref(2, name)),
+        ],
+    );
+    let fs = Filesystem::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let path = OsStr::from_bytes(b"/caf\xff");
+    let files: Vec<_> = fs.files().map(|file| file.unwrap().path).collect();
+    assert_eq!(files, [path]);
+    let files: Vec<_> = fs
+        .files_in_readdir_order()
+        .map(|file| file.unwrap().path)
+        .collect();
+    assert_eq!(files, [path]);
+    assert_eq!(fs.paths(257).unwrap(), [path]);
+    assert_eq!(fs.lookup(path).unwrap(), 257);
+
+    let dest = std::env::temp_dir().join(format!("btrfs-walk-non-utf8-{}", std::process::id()));
+    fs.extract("/", &dest).unwrap();
+    assert!(dest.join(OsStr::from_bytes(name)).is_file());
+    std::fs::remove_dir_all(&dest).unwrap();
+}
@@ -0,0 +1,155 @@
+//! Human readable forms of INODE_ITEM fields, as `ls -l` and `stat` show them
+
+use crate::structs::*;
+
+/// Names of the `BtrfsInodeItem.flags` bits, as `btrfs inspect-internal dump-tree` shows them
+const INODE_FLAGS: [(u64, &str); 13] = [
+    (BTRFS_INODE_NODATASUM, "NODATASUM"),
+    (BTRFS_INODE_NODATACOW, "NODATACOW"),
+    (BTRFS_INODE_READONLY, "READONLY"),
+    (BTRFS_INODE_NOCOMPRESS, "NOCOMPRESS"),
+    (BTRFS_INODE_PREALLOC, "PREALLOC"),
+    (BTRFS_INODE_SYNC, "SYNC"),
+    (BTRFS_INODE_IMMUTABLE, "IMMUTABLE"),
+    (BTRFS_INODE_APPEND, "APPEND"),
+    (BTRFS_INODE_NODUMP, "NODUMP"),
+    (BTRFS_INODE_NOATIME, "NOATIME"),
+    (BTRFS_INODE_DIRSYNC, "DIRSYNC"),
+    (BTRFS_INODE_COMPRESS, "COMPRESS"),
+    (BTRFS_INODE_ROOT_ITEM_INIT, "ROOT_ITEM_INIT"),
+];
+
+/// Format the type and permission bits of `mode` like `ls -l`, eg. `drwxr-xr-x`
+pub fn format_mode(mode: u32) -> String {
+    let ty = match mode & 0o170000 {
+        0o100000 => '-',
+        0o040000 => 'd',
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o010000 => 'p',
+        0o140000 => 's',
+        0o120000 => 'l',
+        _ => '?',
+    };
+
+    let mut out = String::with_capacity(10);
+    out.push(ty);
+    // Owner, group and other, with the setuid, setgid and sticky bit that shows in their
+    // execute position
+    for (shift, special, set) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = mode >> shift;
+        out.push(if bits & 4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 2 != 0 { 'w' } else { '-' });
+        out.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => set.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    out
+}
+
+/// Names of the bits set in inode `flags`. Unknown bits are given in hex.
+pub fn inode_flag_names(flags: u64) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = flags;
+    for (bit, name) in INODE_FLAGS.iter() {
+        if flags & bit != 0 {
+            names.push(name.to_string());
+            rest &= !bit;
+        }
+    }
+    if rest != 0 {
+        names.push(format!("{:#x}", rest));
+    }
+
+    names
+}
+
+/// Split a device number as the kernel stores it in `BtrfsInodeItem.rdev` into major and minor
+pub fn rdev_major_minor(rdev: u64) -> (u64, u64) {
+    (rdev >> 20, rdev & 0xfffff)
+}
+
+/// Format `ts` as an RFC 3339 UTC timestamp with nanoseconds, eg.
+/// `2020-09-13T12:26:40.000000001Z`
+pub fn format_timespec(ts: &BtrfsTimespec) -> String {
+    // Seconds are signed on disk
+    let secs = ts.sec as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        ts.nsec
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date of the proleptic Gregorian
+/// calendar. From Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Count from 0000-03-01 so leap days end each 400 year era
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u32, day as u32)
+}
+
+#[test]
+fn test_format_mode() {
+    assert_eq!(format_mode(0o100644), "-rw-r--r--");
+    assert_eq!(format_mode(0o040755), "drwxr-xr-x");
+    assert_eq!(format_mode(0o104755), "-rwsr-xr-x");
+    assert_eq!(format_mode(0o102644), "-rw-r-Sr--");
+    assert_eq!(format_mode(0o041777), "drwxrwxrwt");
+    assert_eq!(format_mode(0o120777), "lrwxrwxrwx");
+}
+
+#[test]
+fn test_inode_flag_names() {
+    assert!(inode_flag_names(0).is_empty());
+    assert_eq!(
+        inode_flag_names(BTRFS_INODE_NODATACOW | BTRFS_INODE_IMMUTABLE | 1 << 40),
+        ["NODATACOW", "IMMUTABLE", "0x10000000000"]
+    );
+}
+
+#[test]
+fn test_format_timespec() {
+    let ts = |sec: i64, nsec| BtrfsTimespec {
+        sec: sec as u64,
+        nsec,
+    };
+    assert_eq!(format_timespec(&ts(0, 0)), "1970-01-01T00:00:00.000000000Z");
+    assert_eq!(
+        format_timespec(&ts(1600000000, 1)),
+        "2020-09-13T12:26:40.000000001Z"
+    );
+    assert_eq!(
+        format_timespec(&ts(951782400, 0)),
+        "2000-02-29T00:00:00.000000000Z"
+    );
+    assert_eq!(
+        format_timespec(&ts(-1, 0)),
+        "1969-12-31T23:59:59.000000000Z"
+    );
+}
@@ -0,0 +1,41 @@
+//! Walks on-disk btrfs data structures of an unmounted filesystem without external btrfs
+//! libraries or `ioctl(2)` calls.
+//!
+//! ```no_run
+//! use btrfs_walk::Filesystem;
+//!
+//! let fs = Filesystem::open("/dev/sdb")?;
+//! for file in fs.files() {
+//!     println!("{}", file?.path.to_string_lossy());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod chunk_tree;
+pub mod compression;
+pub mod csum;
+mod device;
+mod extent;
+mod extract;
+mod file_type;
+mod filesystem;
+mod inode;
+pub mod parse;
+mod raid;
+pub mod structs;
+mod subvolume;
+mod superblock;
+pub mod tree;
+mod xattrs;
+
+pub use device::{format_uuid, Device, Devices};
+pub use extract::ExtractOptions;
+pub use file_type::FileType;
+pub use filesystem::{
+    DirEntry, FileEntry, Files, Filesystem, FilesystemOptions, InodeRef, ReaddirFiles,
+    DEFAULT_BLOCK_CACHE_SIZE,
+};
+pub use inode::{format_mode, format_timespec, inode_flag_names, rdev_major_minor};
+pub use subvolume::Subvolume;
+pub use superblock::TreeRoots;
+pub use xattrs::Xattr;
@@ -1,459 +1,685 @@
-use std::fs::{File, OpenOptions};
-use std::os::unix::prelude::FileExt;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
-use std::slice;
+use std::str::FromStr;
 
-use anyhow::{anyhow, bail, Result};
-use structopt::StructOpt;
-
-mod structs;
-use structs::*;
-mod chunk_tree;
-use chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeValue};
-mod tree;
+use anyhow::{anyhow, Error, Result};
+use log::{info, warn, Level, LevelFilter};
 
-/// Physical address of the first superblock
-const BTRFS_SUPERBLOCK_OFFSET: u64 = 0x10_000;
-const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
+use btrfs_walk::structs::BtrfsInodeItem;
+use btrfs_walk::{
+    format_mode, format_timespec, format_uuid, inode_flag_names, rdev_major_minor, ExtractOptions,
+    FileEntry, FileType, Filesystem, FilesystemOptions, Xattr,
+};
+use serde_json::{Map, Value};
+use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "btrfs-walk",
-    about = "Prints the absolute path of all regular files in an unmounted btrfs filesystem image"
+    about = "Prints the absolute path of every file and directory in an unmounted btrfs filesystem image"
 )]
 struct Opt {
-    /// Block device or file to process
-    #[structopt(parse(from_os_str))]
-    device: PathBuf,
+    /// Block devices or files to process. A filesystem spanning several devices needs all of
+    /// them, in any order.
+    #[structopt(parse(from_os_str), required = true)]
+    devices: Vec<PathBuf>,
+    /// Use this superblock copy (0 is the primary, 1 and 2 are mirrors) instead of the
+    /// valid copy with the highest generation
+    #[structopt(short = "s", long = "super")]
+    super_mirror: Option<usize>,
+    /// Use this superblock backup root slot (0-3) instead of the current tree roots
+    #[structopt(long)]
+    backup_root: Option<usize>,
+    /// Memory to spend on caching tree blocks, in MiB. 0 disables the cache.
+    #[structopt(long, default_value = "64", parse(try_from_str = parse_mib))]
+    cache_size: usize,
+    /// Only list entries of these types, as letters from `find -type`: f, d, l, b, c, p, s.
+    /// Can be repeated or comma separated.
+    #[structopt(short = "t", long = "type", use_delimiter = true, number_of_values = 1)]
+    types: Vec<FileType>,
+    /// List directory entries in the order `readdir` returns them on a mounted filesystem,
+    /// from DIR_INDEX items, and report entries missing from DIR_ITEM or DIR_INDEX items
+    #[structopt(long)]
+    readdir_order: bool,
+    /// List each file once, followed by the other paths of its hard links as `link=` lines
+    #[structopt(long)]
+    group_links: bool,
+    /// Also print the mode, link count, owner, size, flags and timestamps of each entry's inode
+    #[structopt(short = "l", long)]
+    long: bool,
+    /// Also print the extended attributes of each entry, with ACLs, file capabilities and
+    /// SELinux labels decoded
+    #[structopt(long)]
+    xattrs: bool,
+    /// Format of the listing: text, json for one JSON object per line, or csv
+    #[structopt(long)]
+    format: Option<Format>,
+    /// Only print paths, each followed by a NUL byte instead of a newline, for `xargs -0`
+    #[structopt(short = "0", long = "null", conflicts_with = "format")]
+    null: bool,
+    /// Log more to stderr: -v for tree roots, -vv for every tree block read, -vvv for block
+    /// cache hits
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+    /// Only log errors, not warnings about damaged or missing data
+    #[structopt(short = "q", long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// List every entry if not given
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn parse_superblock(file: &File) -> Result<BtrfsSuperblock> {
-    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
-    let superblock_size = std::mem::size_of::<BtrfsSuperblock>();
-    let slice;
-    unsafe {
-        slice = slice::from_raw_parts_mut(&mut superblock as *mut _ as *mut u8, superblock_size);
-    }
-    file.read_exact_at(slice, BTRFS_SUPERBLOCK_OFFSET)?;
-    if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
-        bail!("superblock magic is wrong");
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Write the contents of a file to stdout
+    Cat {
+        /// Absolute path of the file inside the filesystem
+        #[structopt(parse(from_os_str))]
+        path: OsString,
+    },
+    /// Copy a file or directory tree out of the filesystem
+    Extract {
+        /// Absolute path inside the filesystem
+        #[structopt(parse(from_os_str))]
+        path: OsString,
+        /// Where to create the copy. Must not exist yet.
+        #[structopt(parse(from_os_str))]
+        dest: PathBuf,
+        /// Restore extended attributes, including ACLs, file capabilities and SELinux labels
+        #[structopt(long)]
+        xattrs: bool,
+    },
+    /// List every subvolume and snapshot
+    Subvolumes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "csv" => Format::Csv,
+            _ => {
+                return Err(anyhow!(
+                    "unknown format '{}', expected text, json or csv",
+                    s
+                ))
+            }
+        })
     }
+}
 
-    Ok(superblock)
+/// Print where a tree root lives
+fn print_root(fs: &Filesystem, name: &str, logical: u64) {
+    let (devid, physical) = fs.chunk_map().physical(logical).unwrap_or_default();
+    info!(
+        "{} at logical offset={}, devid={}, physical offset={}, size={}",
+        name,
+        logical,
+        devid,
+        physical,
+        fs.superblock().node_size,
+    );
 }
 
-fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache> {
-    let array_size = superblock.sys_chunk_array_size as usize;
-    let mut offset: usize = 0;
-    let mut chunk_tree_cache = ChunkTreeCache::default();
+/// Parse a size given in MiB into bytes
+fn parse_mib(s: &str) -> Result<usize> {
+    let mib: usize = s.parse()?;
 
-    while offset < array_size {
-        let key_size = std::mem::size_of::<BtrfsKey>();
-        if offset + key_size > array_size as usize {
-            bail!("short key read");
-        }
+    mib.checked_mul(1 << 20)
+        .ok_or_else(|| anyhow!("{} MiB is more than can be addressed", mib))
+}
 
-        let key_slice = &superblock.sys_chunk_array[offset..];
-        let key = unsafe { &*(key_slice.as_ptr() as *const BtrfsKey) };
-        if key.ty != BTRFS_CHUNK_ITEM_KEY {
-            bail!(
-                "unknown item type={} in sys_array at offset={}",
-                key.ty,
-                offset
-            );
-        }
-        offset += key_size;
+/// Log to stderr at the level picked with `-v` and `-q`
+fn init_logging(opt: &Opt) {
+    let level = match (opt.quiet, opt.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| {
+            let level = match record.level() {
+                Level::Warn => "warning".to_string(),
+                level => level.as_str().to_lowercase(),
+            };
+            writeln!(buf, "{}: {}", level, record.args())
+        })
+        .init();
+}
 
-        if offset + std::mem::size_of::<BtrfsChunk>() > array_size {
-            bail!("short chunk item read");
+fn main() {
+    let opt = Opt::from_args();
+    init_logging(&opt);
+
+    let options = FilesystemOptions {
+        super_mirror: opt.super_mirror,
+        backup_root: opt.backup_root,
+        block_cache_size: Some(opt.cache_size),
+    };
+    let fs = Filesystem::open_devices(&opt.devices, &options).expect("failed to open filesystem");
+
+    match &opt.command {
+        None => list_files(&fs, &opt),
+        Some(Command::Cat { path }) => {
+            let (fs, inode) = fs.resolve(path).expect("failed to find file");
+            fs.read_file(inode, &mut std::io::stdout().lock())
+                .expect("failed to read file");
         }
-
-        let chunk_slice = &superblock.sys_chunk_array[offset..];
-        let chunk = unsafe { &*(chunk_slice.as_ptr() as *const BtrfsChunk) };
-        if chunk.num_stripes == 0 {
-            bail!("num_stripes cannot be 0");
+        Some(Command::Extract { path, dest, xattrs }) => {
+            let options = ExtractOptions { xattrs: *xattrs };
+            fs.extract_with_options(path, dest, &options)
+                .expect("failed to extract");
         }
+        Some(Command::Subvolumes) => {
+            for subvolume in fs.subvolumes().expect("failed to list subvolumes") {
+                println!(
+                    "id={} parent={} generation={} uuid={} parent_uuid={} path={}",
+                    subvolume.id,
+                    subvolume.parent,
+                    subvolume.generation,
+                    format_uuid(&subvolume.uuid),
+                    format_uuid(&subvolume.parent_uuid),
+                    subvolume.path.to_string_lossy()
+                );
+            }
+        }
+    }
+}
 
-        // To keep things simple, we'll only process 1 stripe, as stripes should have
-        // identical content. The device the stripe is on will be the device passed in
-        // via cmd line args.
-        let num_stripes = chunk.num_stripes; // copy to prevent unaligned access
-        if num_stripes != 1 {
-            println!(
-                "warning: {} stripes detected but only processing 1",
-                num_stripes
-            );
+fn list_files(fs: &Filesystem, opt: &Opt) {
+    print_root(fs, "chunk tree root", fs.tree_roots().chunk_root);
+    print_root(fs, "root tree root", fs.tree_roots().root);
+    print_root(fs, "fs tree root", fs.fs_tree_root());
+
+    let files: Box<dyn Iterator<Item = _>> = if opt.readdir_order {
+        Box::new(fs.files_in_readdir_order())
+    } else {
+        Box::new(fs.files())
+    };
+    let mut out = Output::new(opt, BufWriter::new(io::stdout())).expect("failed to write output");
+    let mut seen = HashSet::new();
+    // Subvolume of the last entry
+    let mut subvol = fs.clone();
+    for file in files {
+        let file = file.expect("failed to walk fs tree");
+        if !opt.types.is_empty() && !opt.types.contains(&file.ty) {
+            continue;
         }
 
-        // Add chunk to cache if not already in cache
-        let logical = key.offset;
-        if chunk_tree_cache.offset(logical).is_none() {
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: logical,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
+        if file.subvol != subvol.subvol_id() {
+            subvol = fs.subvolume(file.subvol).expect("failed to open subvolume");
         }
 
-        // Despite only processing one stripe, we need to be careful to skip over the
-        // entire chunk item.
-        let chunk_item_size = std::mem::size_of::<BtrfsChunk>()
-            + (std::mem::size_of::<BtrfsStripe>() * (chunk.num_stripes as usize - 1));
-        if offset + chunk_item_size > array_size {
-            bail!("short chunk item + stripe read");
+        if !opt.group_links || file.ty == FileType::Directory {
+            out.write(&subvol, &file, None, opt)
+                .expect("failed to write output");
+            continue;
+        }
+        // The other links were printed with the first one
+        if !seen.insert((file.subvol, file.inode)) {
+            continue;
         }
-        offset += chunk_item_size;
+        let links: Vec<OsString> = subvol
+            .paths(file.inode)
+            .expect("failed to find hard links")
+            .into_iter()
+            .filter(|path| *path != file.path)
+            .collect();
+        out.write(&subvol, &file, Some(links), opt)
+            .expect("failed to write output");
     }
-
-    Ok(chunk_tree_cache)
+    out.finish().expect("failed to write output");
 }
 
-fn read_chunk_tree_root(
-    file: &File,
-    chunk_root_logical: u64,
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let size = cache
-        .mapping_kv(chunk_root_logical)
-        .ok_or_else(|| anyhow!("Chunk tree root not bootstrapped"))?
-        .0
-        .size;
-    let physical = cache
-        .offset(chunk_root_logical)
-        .ok_or_else(|| anyhow!("Chunk tree root not bootstrapped"))?;
-
-    let mut root = vec![0; size as usize];
-    file.read_exact_at(&mut root, physical)?;
-
-    println!(
-        "chunk tree root at logical offset={}, physical offset={}, size={}",
-        chunk_root_logical, physical, size,
-    );
-
-    Ok(root)
+/// Where the listing goes, in the format picked on the command line. Text goes straight to
+/// stdout, the other formats to `W`.
+enum Output<W: Write> {
+    Text,
+    Json(W),
+    Csv(Box<csv::Writer<W>>),
+    Null(W),
 }
 
-fn read_root_tree_root(
-    file: &File,
-    root_tree_root_logical: u64,
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let size = cache
-        .mapping_kv(root_tree_root_logical)
-        .ok_or_else(|| anyhow!("Root tree root logical addr not mapped"))?
-        .0
-        .size;
-    let physical = cache
-        .offset(root_tree_root_logical)
-        .ok_or_else(|| anyhow!("Root tree root logical addr not mapped"))?;
-
-    let mut root = vec![0; size as usize];
-    file.read_exact_at(&mut root, physical)?;
+impl<W: Write> Output<W> {
+    fn new(opt: &Opt, out: W) -> Result<Self> {
+        if opt.null {
+            return Ok(Output::Null(out));
+        }
 
-    println!(
-        "root tree root at logical offset={}, physical offset={}, size={}",
-        root_tree_root_logical, physical, size,
-    );
+        Ok(match opt.format.unwrap_or(Format::Text) {
+            Format::Text => Output::Text,
+            Format::Json => Output::Json(out),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record(columns(opt))?;
+                Output::Csv(Box::new(writer))
+            }
+        })
+    }
 
-    Ok(root)
-}
+    /// Write the entry of `file`, which is in subvolume `fs`. `links` are the other paths of
+    /// the file if links are grouped.
+    fn write(
+        &mut self,
+        fs: &Filesystem,
+        file: &FileEntry,
+        links: Option<Vec<OsString>>,
+        opt: &Opt,
+    ) -> Result<()> {
+        match self {
+            Output::Text => {
+                print_file(fs, file, opt);
+                for path in links.unwrap_or_default() {
+                    println!("  link={}", path.to_string_lossy());
+                }
+                if opt.xattrs {
+                    match fs.xattrs(file.inode) {
+                        Ok(xattrs) => {
+                            for xattr in xattrs {
+                                println!(
+                                    "  {}={}",
+                                    xattr.name.to_string_lossy(),
+                                    xattr.display_value()
+                                );
+                            }
+                        }
+                        Err(e) => warn!(
+                            "failed to read xattrs of {}: {:#}",
+                            file.path.to_string_lossy(),
+                            e
+                        ),
+                    }
+                }
+            }
+            // Paths only
+            Output::Null(_) => self.write_entry(file, Vec::new(), opt)?,
+            _ => self.write_entry(file, fields(fs, file, links, opt), opt)?,
+        }
 
-fn read_chunk_tree(
-    file: &File,
-    root: &[u8],
-    chunk_tree_cache: &mut ChunkTreeCache,
-    superblock: &BtrfsSuperblock,
-) -> Result<()> {
-    let header = tree::parse_btrfs_header(root).expect("failed to parse chunk root header");
-    unsafe {
-        println!(
-            "chunk tree node level={}, bytenr={}, nritems={}",
-            header.level, header.bytenr, header.nritems
-        );
+        Ok(())
     }
 
-    // Level 0 is leaf node, !0 is internal node
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(root)?;
-        for item in items {
-            if item.key.ty != BTRFS_CHUNK_ITEM_KEY {
-                continue;
+    /// Write the JSON or CSV record made of `fields`, or the path of `file` for `-0`
+    fn write_entry(
+        &mut self,
+        file: &FileEntry,
+        fields: Vec<(&'static str, Field)>,
+        opt: &Opt,
+    ) -> Result<()> {
+        match self {
+            Output::Text => {}
+            Output::Json(out) => {
+                let map: Map<String, Value> = fields
+                    .into_iter()
+                    .map(|(name, field)| (name.to_string(), field.into_json()))
+                    .collect();
+                serde_json::to_writer(&mut *out, &map)?;
+                out.write_all(b"\n")?;
+            }
+            Output::Csv(writer) => {
+                let record = columns(opt).into_iter().map(|column| {
+                    fields
+                        .iter()
+                        .find(|(name, _)| *name == column)
+                        .map(|(_, field)| field.to_csv())
+                        .unwrap_or_default()
+                });
+                writer.write_record(record)?;
+            }
+            Output::Null(out) => {
+                out.write_all(file.path.as_bytes())?;
+                out.write_all(b"\0")?;
             }
+        }
 
-            let chunk = unsafe {
-                // `item.offset` is offset from data portion of `BtrfsLeaf` where associated
-                // `BtrfsChunk` starts
-                &*(root
-                    .as_ptr()
-                    .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                    as *const BtrfsChunk)
-            };
+        Ok(())
+    }
 
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: item.key.offset,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
-        }
-    } else {
-        let ptrs = tree::parse_btrfs_node(root)?;
-        for ptr in ptrs {
-            let physical = chunk_tree_cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("Chunk tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            read_chunk_tree(file, &node, chunk_tree_cache, superblock)?;
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::Text => {}
+            Output::Json(mut out) | Output::Null(mut out) => out.flush()?,
+            Output::Csv(mut writer) => writer.flush()?,
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+/// Value of a JSON or CSV field
+enum Field {
+    Str(String),
+    Num(u64),
+    List(Vec<String>),
+    /// Like `List`, for items that may contain commas
+    Paths(Vec<String>),
+    /// Names and values
+    Map(Vec<(String, String)>),
 }
 
-fn read_fs_tree_root(
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    root_tree_root: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let header =
-        tree::parse_btrfs_header(root_tree_root).expect("failed to parse root tree root header");
-    unsafe {
-        println!(
-            "root tree root level={}, bytenr={}, nritems={}",
-            header.level, header.bytenr, header.nritems
-        );
+impl Field {
+    fn into_json(self) -> Value {
+        match self {
+            Field::Str(s) => s.into(),
+            Field::Num(n) => n.into(),
+            Field::List(list) | Field::Paths(list) => list.into(),
+            Field::Map(map) => map
+                .into_iter()
+                .map(|(name, value)| (name, value.into()))
+                .collect::<Map<String, Value>>()
+                .into(),
+        }
     }
 
-    if header.level != 0 {
-        bail!("Root tree root is not a leaf node");
+    /// Lists go in one cell, comma separated or one item per line
+    fn to_csv(&self) -> String {
+        match self {
+            Field::Str(s) => s.clone(),
+            Field::Num(n) => n.to_string(),
+            Field::List(list) => list.join(","),
+            Field::Paths(paths) => paths.join("\n"),
+            Field::Map(map) => map
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
     }
+}
 
-    let items = tree::parse_btrfs_leaf(root_tree_root)?;
-    for item in items.iter().rev() {
-        if item.key.objectid != BTRFS_FS_TREE_OBJECTID || item.key.ty != BTRFS_ROOT_ITEM_KEY {
-            continue;
-        }
+/// Fields the inode metadata adds with `--long`
+const LONG_COLUMNS: [&str; 12] = [
+    "mode", "nlink", "uid", "gid", "size", "nbytes", "rdev", "flags", "atime", "ctime", "mtime",
+    "otime",
+];
+
+/// Names of the CSV columns
+fn columns(opt: &Opt) -> Vec<&'static str> {
+    let mut columns = vec!["path", "inode", "subvol", "type"];
+    if opt.long {
+        columns.extend_from_slice(&LONG_COLUMNS);
+    }
+    if opt.group_links {
+        columns.push("links");
+    }
+    if opt.xattrs {
+        columns.push("xattrs");
+    }
+    if opt.long || opt.xattrs {
+        columns.push("error");
+    }
 
-        let root_item = unsafe {
-            &*(root_tree_root
-                .as_ptr()
-                .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                as *const BtrfsRootItem)
-        };
+    columns
+}
 
-        let physical = cache
-            .offset(root_item.bytenr)
-            .ok_or_else(|| anyhow!("fs tree root not mapped"))?;
-        let mut node = vec![0; superblock.node_size as usize];
-        file.read_exact_at(&mut node, physical)?;
+/// Fields of the JSON or CSV entry of `file`, which is in subvolume `fs`
+fn fields(
+    fs: &Filesystem,
+    file: &FileEntry,
+    links: Option<Vec<OsString>>,
+    opt: &Opt,
+) -> Vec<(&'static str, Field)> {
+    let inode = if opt.long {
+        Some(fs.inode_item(file.inode))
+    } else {
+        None
+    };
+    let xattrs = if opt.xattrs {
+        Some(fs.xattrs(file.inode))
+    } else {
+        None
+    };
 
-        unsafe {
-            println!(
-                "fs tree root at logical offset={}, physical offset={}, size={}",
-                root_item.bytenr, physical, superblock.node_size,
-            );
-        }
+    entry_fields(file, inode, links, xattrs)
+}
 
-        return Ok(node);
+/// Fields of the JSON or CSV entry of `file`, with its inode item if `--long` is given and its
+/// extended attributes if `--xattrs` is. Lookups that failed end up in one `error` field.
+fn entry_fields(
+    file: &FileEntry,
+    inode: Option<Result<BtrfsInodeItem>>,
+    links: Option<Vec<OsString>>,
+    xattrs: Option<Result<Vec<Xattr>>>,
+) -> Vec<(&'static str, Field)> {
+    let mut fields = vec![
+        ("path", Field::Str(escape_name(&file.path))),
+        ("inode", Field::Num(file.inode)),
+        ("subvol", Field::Num(file.subvol)),
+        ("type", Field::Str(file.ty.to_string())),
+    ];
+    let mut errors = Vec::new();
+    match inode {
+        Some(Ok(inode)) => {
+            let (major, minor) = rdev_major_minor(inode.rdev);
+            fields.extend(vec![
+                ("mode", Field::Str(format_mode(inode.mode))),
+                ("nlink", Field::Num(inode.nlink.into())),
+                ("uid", Field::Num(inode.uid.into())),
+                ("gid", Field::Num(inode.gid.into())),
+                ("size", Field::Num(inode.size)),
+                ("nbytes", Field::Num(inode.nbytes)),
+                ("rdev", Field::Str(format!("{}:{}", major, minor))),
+                ("flags", Field::List(inode_flag_names(inode.flags))),
+                ("atime", Field::Str(format_timespec(&inode.atime))),
+                ("ctime", Field::Str(format_timespec(&inode.ctime))),
+                ("mtime", Field::Str(format_timespec(&inode.mtime))),
+                ("otime", Field::Str(format_timespec(&inode.otime))),
+            ]);
+        }
+        Some(Err(e)) => errors.push(format!("{:#}", e)),
+        None => {}
+    }
+    if let Some(links) = links {
+        let links = links.iter().map(|path| escape_name(path)).collect();
+        fields.push(("links", Field::Paths(links)));
+    }
+    match xattrs {
+        Some(Ok(xattrs)) => fields.push((
+            "xattrs",
+            Field::Map(
+                xattrs
+                    .iter()
+                    .map(|xattr| (escape_name(&xattr.name), xattr.display_value()))
+                    .collect(),
+            ),
+        )),
+        Some(Err(e)) => errors.push(format!("{:#}", e)),
+        None => {}
+    }
+    if !errors.is_empty() {
+        fields.push(("error", Field::Str(errors.join("; "))));
     }
 
-    bail!("Failed to find root tree item for fs tree root");
+    fields
 }
 
-/// Returns `BtrfsInodeRef` and payload associated with `inode` number. Also returns `BtrfsKey`
-/// associated with the `BtrfsInodeRef`.
-fn get_inode_ref(
-    inode: u64,
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    node: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<Option<(BtrfsKey, BtrfsInodeRef, Vec<u8>)>> {
-    let header = tree::parse_btrfs_header(node)?;
-    // Leaf node
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(node)?;
-        for item in items {
-            if item.key.ty != BTRFS_INODE_REF_KEY {
-                continue;
-            }
-
-            if item.key.objectid == inode {
-                let inode_ref = unsafe {
-                    &*(node
-                        .as_ptr()
-                        .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                        as *const BtrfsInodeRef)
-                };
-
-                let inode_ref_payload = unsafe {
-                    std::slice::from_raw_parts(
-                        (inode_ref as *const BtrfsInodeRef as *const u8)
-                            .add(std::mem::size_of::<BtrfsInodeRef>()),
-                        inode_ref.name_len.into(),
-                    )
-                };
-
-                return Ok(Some((item.key, *inode_ref, inode_ref_payload.into())));
-            }
+/// `name`, a path or xattr name, as text for JSON and CSV. Bytes that aren't UTF-8 are
+/// written as `\xNN` and backslashes are doubled, so every name maps to a distinct string.
+fn escape_name(name: &OsStr) -> String {
+    let mut escaped = String::new();
+    for chunk in name.as_bytes().utf8_chunks() {
+        escaped.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{:02x}", byte);
         }
-
-        Ok(None)
-    } else {
-        let ptrs = tree::parse_btrfs_node(node)?;
-        for ptr in ptrs {
-            let physical = cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("fs tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            let ret = get_inode_ref(inode, file, superblock, &node, cache)?;
-            if ret.is_some() {
-                return Ok(ret);
-            }
-        }
-
-        Ok(None)
     }
+    escaped
 }
 
-fn walk_fs_tree(
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    node: &[u8],
-    root_fs_node: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<()> {
-    let header = tree::parse_btrfs_header(node)?;
-    unsafe {
-        println!(
-            "fs tree node level={}, bytenr={}, nritems={}",
-            header.level, header.bytenr, header.nritems
-        );
+/// Print the line of `file`, which is in subvolume `fs`
+fn print_file(fs: &Filesystem, file: &FileEntry, opt: &Opt) {
+    if !opt.long {
+        println!("filename={} type={}", file.path.to_string_lossy(), file.ty);
+        return;
     }
 
-    // Leaf node
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(node)?;
-        for item in items {
-            if item.key.ty != BTRFS_DIR_ITEM_KEY {
-                continue;
-            }
-
-            let dir_item = unsafe {
-                &*(node
-                    .as_ptr()
-                    .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                    as *const BtrfsDirItem)
-            };
+    let inode = match fs.inode_item(file.inode) {
+        Ok(inode) => inode,
+        Err(e) => {
+            println!(
+                "filename={} type={} error={:#}",
+                file.path.to_string_lossy(),
+                file.ty,
+                e
+            );
+            return;
+        }
+    };
+    let flags = inode_flag_names(inode.flags);
+    let (major, minor) = rdev_major_minor(inode.rdev);
+    println!(
+        "filename={} type={} mode={} nlink={} uid={} gid={} size={} nbytes={} rdev={}:{} \
+         flags={} atime={} ctime={} mtime={} otime={}",
+        file.path.to_string_lossy(),
+        file.ty,
+        format_mode(inode.mode),
+        inode.nlink,
+        inode.uid,
+        inode.gid,
+        inode.size,
+        inode.nbytes,
+        major,
+        minor,
+        if flags.is_empty() {
+            "none".to_string()
+        } else {
+            flags.join(",")
+        },
+        format_timespec(&inode.atime),
+        format_timespec(&inode.ctime),
+        format_timespec(&inode.mtime),
+        format_timespec(&inode.otime),
+    );
+}
 
-            if dir_item.ty != BTRFS_FT_REG_FILE {
-                continue;
-            }
+/// Write the entry of a file whose path needs escaping in every format, with `args` on the
+/// command line and `--long` and `--xattrs` lookups that failed
+#[cfg(test)]
+fn test_output(args: &[&str]) -> Vec<u8> {
+    let opt = Opt::from_iter(["btrfs-walk", "img"].iter().chain(args));
+    let file = FileEntry {
+        path: "/say \"hi\",\nbye".into(),
+        subvol: 5,
+        inode: 257,
+        ty: FileType::Regular,
+    };
+    let inode = if opt.long {
+        Some(Err(anyhow!("inode=257 not found")))
+    } else {
+        None
+    };
+    let xattrs = if opt.xattrs {
+        Some(Err(anyhow!("bad xattr item")))
+    } else {
+        None
+    };
+    let links = if opt.group_links {
+        Some(vec!["/a,b".into(), "/c".into()])
+    } else {
+        None
+    };
+
+    let mut buf = Vec::new();
+    let mut out = Output::new(&opt, &mut buf).unwrap();
+    let fields = entry_fields(&file, inode, links, xattrs);
+    out.write_entry(&file, fields, &opt).unwrap();
+    out.finish().unwrap();
+    buf
+}
 
-            let name_slice = unsafe {
-                std::slice::from_raw_parts(
-                    (dir_item as *const BtrfsDirItem as *const u8)
-                        .add(std::mem::size_of::<BtrfsDirItem>()),
-                    dir_item.name_len.into(),
-                )
-            };
-            let name = std::str::from_utf8(name_slice)?;
-
-            // Now compute the path leading up to current file
-            //
-            // Capacity 1 so we don't panic the first `String::insert`
-            let mut path_prefix = String::with_capacity(1);
-            // `item.key.objectid` is parent inode number
-            let mut current_inode_nr = item.key.objectid;
-            loop {
-                let (current_key, _current_inode, current_inode_payload) =
-                    get_inode_ref(current_inode_nr, file, superblock, root_fs_node, cache)?
-                        .ok_or_else(|| {
-                            anyhow!("Failed to find inode_ref for inode={}", current_inode_nr)
-                        })?;
-                unsafe { assert_eq!(current_key.objectid, current_inode_nr) };
-
-                // `current_key.offset` is parent inode # of `current_inode`
-                if current_key.offset == current_inode_nr {
-                    // If parent inode # and current inode # match, that means ".." points to
-                    // itself and we've hit the filesystem root.
-                    path_prefix.insert(0, '/');
-                    break;
-                }
+#[test]
+fn test_json_output() {
+    assert_eq!(
+        String::from_utf8(test_output(&["--format", "json"])).unwrap(),
+        "{\"path\":\"/say \\\"hi\\\",\\nbye\",\"inode\":257,\"subvol\":5,\"type\":\"f\"}\n"
+    );
 
-                path_prefix.insert_str(
-                    0,
-                    &format!("{}/", std::str::from_utf8(&current_inode_payload)?),
-                );
+    // Both failed lookups are kept
+    let out = test_output(&["--format", "json", "-l", "--xattrs", "--group-links"]);
+    let entry: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(entry["links"], serde_json::json!(["/a,b", "/c"]));
+    assert_eq!(entry["error"], "inode=257 not found; bad xattr item");
+}
 
-                current_inode_nr = current_key.offset;
-            }
+#[test]
+fn test_csv_output() {
+    assert_eq!(
+        String::from_utf8(test_output(&["--format", "csv"])).unwrap(),
+        "path,inode,subvol,type\n\"/say \"\"hi\"\",\nbye\",257,5,f\n"
+    );
 
-            println!("filename={}{}", path_prefix, name);
-        }
-    } else {
-        let ptrs = tree::parse_btrfs_node(node)?;
-        for ptr in ptrs {
-            let physical = cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("fs tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            walk_fs_tree(file, superblock, &node, root_fs_node, cache)?;
-        }
-    }
+    let out = test_output(&["--format", "csv", "-l", "--xattrs", "--group-links"]);
+    let mut reader = csv::Reader::from_reader(&out[..]);
+    let headers = reader.headers().unwrap().clone();
+    let record = reader.records().next().unwrap().unwrap();
+    let field = |name| record.get(headers.iter().position(|h| h == name).unwrap());
+    assert_eq!(field("path"), Some("/say \"hi\",\nbye"));
+    // No inode item, so the inode columns are empty
+    assert_eq!(field("mode"), Some(""));
+    assert_eq!(field("links"), Some("/a,b\n/c"));
+    assert_eq!(field("error"), Some("inode=257 not found; bad xattr item"));
+}
 
-    Ok(())
+#[test]
+fn test_null_output() {
+    assert_eq!(test_output(&["-0"]), b"/say \"hi\",\nbye\0");
 }
 
-fn main() {
-    let opt = Opt::from_args();
+#[test]
+fn test_non_utf8_path() {
+    let file = FileEntry {
+        path: OsStr::from_bytes(b"/caf\xff\\x").into(),
+        subvol: 5,
+        inode: 257,
+        ty: FileType::Regular,
+    };
+    let links = Some(vec![OsStr::from_bytes(b"/\xfe").into()]);
+    let write = |args: &[&str]| {
+        let opt = Opt::from_iter(["btrfs-walk", "img"].iter().chain(args));
+        let mut buf = Vec::new();
+        let mut out = Output::new(&opt, &mut buf).unwrap();
+        let fields = entry_fields(&file, None, links.clone(), None);
+        out.write_entry(&file, fields, &opt).unwrap();
+        out.finish().unwrap();
+        buf
+    };
+
+    let entry: Value = serde_json::from_slice(&write(&["--format", "json"])).unwrap();
+    assert_eq!(entry["path"], "/caf\\xff\\\\x");
+    assert_eq!(entry["links"], serde_json::json!(["/\\xfe"]));
+    assert_eq!(
+        write(&["--format", "csv", "--group-links"]),
+        b"path,inode,subvol,type,links\n/caf\\xff\\\\x,257,5,f,/\\xfe\n"
+    );
+    // Raw bytes
+    assert_eq!(write(&["-0"]), b"/caf\xff\\x\0");
+}
 
-    let file = OpenOptions::new()
-        .read(true)
-        .open(opt.device.as_path())
-        .expect("Failed to open path");
-
-    // Read superblock
-    let superblock = parse_superblock(&file).expect("failed to parse superblock");
-
-    // Bootstrap chunk tree
-    let mut chunk_tree_cache =
-        bootstrap_chunk_tree(&superblock).expect("failed to bootstrap chunk tree");
-
-    // Read root chunk tree node
-    let chunk_root = read_chunk_tree_root(&file, superblock.chunk_root, &chunk_tree_cache)
-        .expect("failed to read chunk tree root");
-
-    // Read rest of chunk tree
-    read_chunk_tree(&file, &chunk_root, &mut chunk_tree_cache, &superblock)
-        .expect("failed to read chunk tree");
-
-    // Read root tree root node
-    let root_tree_root = read_root_tree_root(&file, superblock.root, &chunk_tree_cache)
-        .expect("failed to read root tree root");
-
-    // Read filesystem tree root node
-    let fs_tree_root = read_fs_tree_root(&file, &superblock, &root_tree_root, &chunk_tree_cache)
-        .expect("failed to read fs tree root");
-
-    // Now start walking fs tree
-    walk_fs_tree(
-        &file,
-        &superblock,
-        &fs_tree_root,
-        &fs_tree_root,
-        &chunk_tree_cache,
-    )
-    .expect("failed to walk fs tree");
+#[test]
+fn test_cache_size() {
+    let cache_size = |size: &str| {
+        Opt::from_iter_safe(&["btrfs-walk", "img", "--cache-size", size]).map(|opt| opt.cache_size)
+    };
+    assert_eq!(cache_size("64").unwrap(), 64 << 20);
+    assert_eq!(cache_size("0").unwrap(), 0);
+    let e = cache_size(&usize::MAX.to_string()).unwrap_err();
+    assert!(e.message.contains("MiB is more than can be addressed"));
+    assert!(cache_size("lots").is_err());
 }
@@ -0,0 +1,115 @@
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+/// Cursor over a byte slice that decodes little-endian integers and fails instead of reading
+/// out of bounds
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    /// Number of bytes consumed so far
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    /// Consume the next `len` bytes
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            bail!(
+                "short read: wanted {} bytes at offset={} but only {} remain",
+                len,
+                self.offset,
+                self.remaining()
+            );
+        }
+
+        let ret = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(ret)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+}
+
+/// An on-disk structure that can be decoded from little-endian bytes
+pub trait Parse: Sized {
+    /// Size of the structure on disk
+    const SIZE: usize;
+
+    /// Decode the structure at the current position of `reader`
+    fn read(reader: &mut Reader) -> Result<Self>;
+
+    /// Decode the structure at the start of `buf`
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::SIZE {
+            bail!(
+                "buffer of {} bytes too small for {} byte structure",
+                buf.len(),
+                Self::SIZE
+            );
+        }
+
+        Self::read(&mut Reader::new(buf))
+    }
+}
+
+impl Parse for u64 {
+    const SIZE: usize = 8;
+
+    fn read(reader: &mut Reader) -> Result<Self> {
+        reader.u64()
+    }
+}
+
+/// Decode `N` consecutive structures
+pub fn read_array<T: Parse, const N: usize>(reader: &mut Reader) -> Result<[T; N]> {
+    let items = (0..N)
+        .map(|_| T::read(reader))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(items.try_into().unwrap_or_else(|_| unreachable!()))
+}
+
+#[test]
+fn test_reader_little_endian() {
+    let buf = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
+    let mut r = Reader::new(&buf);
+    assert_eq!(r.u8().unwrap(), 0x01);
+    assert_eq!(r.u16().unwrap(), 0x0302);
+    assert_eq!(r.u32().unwrap(), 0x0706_0504);
+    assert_eq!(r.remaining(), 2);
+    assert!(r.u64().is_err());
+    // Failed reads don't consume anything
+    assert_eq!(r.offset(), 7);
+    assert_eq!(r.bytes(2).unwrap(), &[0x08, 0x09]);
+}
@@ -0,0 +1,172 @@
+//! RAID5/RAID6 parity math. Matches the kernel's lib/raid6: P is the xor of all data stripes
+//! and Q is sum(g^i * D_i) over GF(2^8) with generator g = 2 and polynomial 0x11d.
+
+use anyhow::{bail, Result};
+
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+
+    (exp, log)
+}
+
+const GF_TABLES: ([u8; 512], [u8; 256]) = gf_tables();
+const GF_EXP: [u8; 512] = GF_TABLES.0;
+const GF_LOG: [u8; 256] = GF_TABLES.1;
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    assert_ne!(b, 0);
+    if a == 0 {
+        return 0;
+    }
+
+    GF_EXP[GF_LOG[a as usize] as usize + 255 - GF_LOG[b as usize] as usize]
+}
+
+/// g^n
+fn gf_pow2(n: usize) -> u8 {
+    GF_EXP[n % 255]
+}
+
+/// Rebuild data stripe `index` of a RAID5/6 full stripe. `data` holds every data stripe of the
+/// full stripe, with `None` for the ones that could not be read. `q` is always `None` for RAID5.
+pub fn reconstruct(
+    index: usize,
+    data: &[Option<Vec<u8>>],
+    p: Option<&[u8]>,
+    q: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    let missing: Vec<usize> = (0..data.len()).filter(|i| data[*i].is_none()).collect();
+    if !missing.contains(&index) {
+        bail!("data stripe={} is not missing", index);
+    }
+    let len = match (p, q) {
+        (Some(p), _) => p.len(),
+        (None, Some(q)) => q.len(),
+        (None, None) => bail!("both parity stripes are missing"),
+    };
+
+    // Xor of the surviving data stripes, and the same weighted by g^i for Q
+    let mut p_partial = vec![0u8; len];
+    let mut q_partial = vec![0u8; len];
+    for (i, stripe) in data.iter().enumerate() {
+        if let Some(stripe) = stripe {
+            let coef = gf_pow2(i);
+            for (j, byte) in stripe.iter().enumerate().take(len) {
+                p_partial[j] ^= byte;
+                q_partial[j] ^= gf_mul(coef, *byte);
+            }
+        }
+    }
+
+    match (missing.len(), p, q) {
+        // D_x = P + sum(D_i)
+        (1, Some(p), _) => Ok(p.iter().zip(p_partial).map(|(p, d)| p ^ d).collect()),
+        // D_x = (Q + sum(g^i * D_i)) / g^x
+        (1, None, Some(q)) => {
+            let coef = gf_pow2(index);
+            Ok(q.iter()
+                .zip(q_partial)
+                .map(|(q, d)| gf_div(q ^ d, coef))
+                .collect())
+        }
+        // With p' = D_x + D_y and q' = g^x * D_x + g^y * D_y:
+        // D_x = (q' + g^y * p') / (g^x + g^y), D_y = p' + D_x
+        (2, Some(p), Some(q)) => {
+            let (x, y) = (missing[0], missing[1]);
+            let (gx, gy) = (gf_pow2(x), gf_pow2(y));
+            let denom = gx ^ gy;
+            let mut ret = Vec::with_capacity(len);
+            for j in 0..len {
+                let pxy = p[j] ^ p_partial[j];
+                let qxy = q[j] ^ q_partial[j];
+                let dx = gf_div(qxy ^ gf_mul(gy, pxy), denom);
+                ret.push(if index == x { dx } else { pxy ^ dx });
+            }
+
+            Ok(ret)
+        }
+        (n, _, _) => bail!("cannot rebuild {} missing data stripes from parity", n),
+    }
+}
+
+#[cfg(test)]
+fn parity(data: &[Vec<u8>]) -> (Vec<u8>, Vec<u8>) {
+    let len = data[0].len();
+    let mut p = vec![0; len];
+    let mut q = vec![0; len];
+    for (i, stripe) in data.iter().enumerate() {
+        for j in 0..len {
+            p[j] ^= stripe[j];
+            q[j] ^= gf_mul(gf_pow2(i), stripe[j]);
+        }
+    }
+
+    (p, q)
+}
+
+#[test]
+fn test_raid5_reconstruct() {
+    let data: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i * 7 + 1, i, 0xff, 0x42]).collect();
+    let (p, _) = parity(&data);
+    for lost in 0..data.len() {
+        let mut survivors: Vec<_> = data.iter().cloned().map(Some).collect();
+        survivors[lost] = None;
+        assert_eq!(
+            reconstruct(lost, &survivors, Some(&p), None).unwrap(),
+            data[lost]
+        );
+    }
+}
+
+#[test]
+fn test_raid6_reconstruct() {
+    let data: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i * 31 + 3, !i, 0x80, i]).collect();
+    let (p, q) = parity(&data);
+
+    // One data stripe and P lost
+    let mut survivors: Vec<_> = data.iter().cloned().map(Some).collect();
+    survivors[2] = None;
+    assert_eq!(reconstruct(2, &survivors, None, Some(&q)).unwrap(), data[2]);
+
+    // Two data stripes lost
+    for x in 0..data.len() {
+        for y in x + 1..data.len() {
+            let mut survivors: Vec<_> = data.iter().cloned().map(Some).collect();
+            survivors[x] = None;
+            survivors[y] = None;
+            assert_eq!(
+                reconstruct(x, &survivors, Some(&p), Some(&q)).unwrap(),
+                data[x]
+            );
+            assert_eq!(
+                reconstruct(y, &survivors, Some(&p), Some(&q)).unwrap(),
+                data[y]
+            );
+        }
+    }
+
+    // Three is too many
+    let survivors = vec![None, None, None, Some(data[3].clone())];
+    assert!(reconstruct(0, &survivors, Some(&p), Some(&q)).is_err());
+}
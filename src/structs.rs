@@ -1,19 +1,88 @@
-const BTRFS_CSUM_SIZE: usize = 32;
+use anyhow::Result;
+
+use crate::parse::{read_array, Parse, Reader};
+
+pub const BTRFS_CSUM_SIZE: usize = 32;
 const BTRFS_LABEL_SIZE: usize = 256;
-const BTRFS_FSID_SIZE: usize = 16;
-const BTRFS_UUID_SIZE: usize = 16;
+pub const BTRFS_FSID_SIZE: usize = 16;
+pub const BTRFS_UUID_SIZE: usize = 16;
 const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
 
 pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+/// Inode number of the top level directory of every fs tree
+pub const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+/// Highest objectid a subvolume can have
+pub const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
 
+pub const BTRFS_INODE_ITEM_KEY: u8 = 1;
 pub const BTRFS_INODE_REF_KEY: u8 = 12;
+pub const BTRFS_INODE_EXTREF_KEY: u8 = 13;
+pub const BTRFS_XATTR_ITEM_KEY: u8 = 24;
 pub const BTRFS_DIR_ITEM_KEY: u8 = 84;
+pub const BTRFS_DIR_INDEX_KEY: u8 = 96;
+pub const BTRFS_EXTENT_DATA_KEY: u8 = 108;
 pub const BTRFS_ROOT_ITEM_KEY: u8 = 132;
+pub const BTRFS_ROOT_BACKREF_KEY: u8 = 144;
+pub const BTRFS_ROOT_REF_KEY: u8 = 156;
+pub const BTRFS_DEV_ITEM_KEY: u8 = 216;
 pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228;
 
 pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+pub const BTRFS_FT_XATTR: u8 = 8;
+
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+/// `BtrfsInodeItem.flags` bits
+pub const BTRFS_INODE_NODATASUM: u64 = 1 << 0;
+pub const BTRFS_INODE_NODATACOW: u64 = 1 << 1;
+pub const BTRFS_INODE_READONLY: u64 = 1 << 2;
+pub const BTRFS_INODE_NOCOMPRESS: u64 = 1 << 3;
+pub const BTRFS_INODE_PREALLOC: u64 = 1 << 4;
+pub const BTRFS_INODE_SYNC: u64 = 1 << 5;
+pub const BTRFS_INODE_IMMUTABLE: u64 = 1 << 6;
+pub const BTRFS_INODE_APPEND: u64 = 1 << 7;
+pub const BTRFS_INODE_NODUMP: u64 = 1 << 8;
+pub const BTRFS_INODE_NOATIME: u64 = 1 << 9;
+pub const BTRFS_INODE_DIRSYNC: u64 = 1 << 10;
+pub const BTRFS_INODE_COMPRESS: u64 = 1 << 11;
+pub const BTRFS_INODE_ROOT_ITEM_INIT: u64 = 1 << 31;
+
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+
+pub const BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF: u64 = 1 << 6;
+pub const BTRFS_FEATURE_INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+
+pub const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+pub const BTRFS_BLOCK_GROUP_PROFILE_MASK: u64 = BTRFS_BLOCK_GROUP_RAID0
+    | BTRFS_BLOCK_GROUP_RAID1
+    | BTRFS_BLOCK_GROUP_DUP
+    | BTRFS_BLOCK_GROUP_RAID10
+    | BTRFS_BLOCK_GROUP_RAID5
+    | BTRFS_BLOCK_GROUP_RAID6
+    | BTRFS_BLOCK_GROUP_RAID1C3
+    | BTRFS_BLOCK_GROUP_RAID1C4;
 
-#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsDevItem {
     /// the internal btrfs device id
@@ -46,7 +115,29 @@ pub struct BtrfsDevItem {
     pub fsid: [u8; BTRFS_UUID_SIZE],
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsDevItem {
+    const SIZE: usize = 98;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            devid: r.u64()?,
+            total_bytes: r.u64()?,
+            bytes_used: r.u64()?,
+            io_align: r.u32()?,
+            io_width: r.u32()?,
+            sector_size: r.u32()?,
+            ty: r.u64()?,
+            generation: r.u64()?,
+            start_offset: r.u64()?,
+            dev_group: r.u32()?,
+            seek_speed: r.u8()?,
+            bandwidth: r.u8()?,
+            uuid: r.array()?,
+            fsid: r.array()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsRootBackup {
     pub tree_root: u64,
@@ -76,7 +167,38 @@ pub struct BtrfsRootBackup {
     pub unused_8: [u8; 10],
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsRootBackup {
+    const SIZE: usize = 168;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            tree_root: r.u64()?,
+            tree_root_gen: r.u64()?,
+            chunk_root: r.u64()?,
+            chunk_root_gen: r.u64()?,
+            extent_root: r.u64()?,
+            extent_root_gen: r.u64()?,
+            fs_root: r.u64()?,
+            fs_root_gen: r.u64()?,
+            dev_root: r.u64()?,
+            dev_root_gen: r.u64()?,
+            csum_root: r.u64()?,
+            csum_root_gen: r.u64()?,
+            total_bytes: r.u64()?,
+            bytes_used: r.u64()?,
+            num_devices: r.u64()?,
+            unused_64: read_array(r)?,
+            tree_root_level: r.u8()?,
+            chunk_root_level: r.u8()?,
+            extent_root_level: r.u8()?,
+            fs_root_level: r.u8()?,
+            dev_root_level: r.u8()?,
+            csum_root_level: r.u8()?,
+            unused_8: r.array()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsSuperblock {
     pub csum: [u8; BTRFS_CSUM_SIZE],
@@ -122,7 +244,50 @@ pub struct BtrfsSuperblock {
     pub root_backups: [BtrfsRootBackup; 4],
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsSuperblock {
+    const SIZE: usize = 3531;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            csum: r.array()?,
+            fsid: r.array()?,
+            bytenr: r.u64()?,
+            flags: r.u64()?,
+            magic: r.array()?,
+            generation: r.u64()?,
+            root: r.u64()?,
+            chunk_root: r.u64()?,
+            log_root: r.u64()?,
+            log_root_transid: r.u64()?,
+            total_bytes: r.u64()?,
+            bytes_used: r.u64()?,
+            root_dir_objectid: r.u64()?,
+            num_devices: r.u64()?,
+            sector_size: r.u32()?,
+            node_size: r.u32()?,
+            leafsize: r.u32()?,
+            stripesize: r.u32()?,
+            sys_chunk_array_size: r.u32()?,
+            chunk_root_generation: r.u64()?,
+            compat_flags: r.u64()?,
+            compat_ro_flags: r.u64()?,
+            incompat_flags: r.u64()?,
+            csum_type: r.u16()?,
+            root_level: r.u8()?,
+            chunk_root_level: r.u8()?,
+            log_root_level: r.u8()?,
+            dev_item: BtrfsDevItem::read(r)?,
+            label: r.array()?,
+            cache_generation: r.u64()?,
+            uuid_tree_generation: r.u64()?,
+            metadata_uuid: r.array()?,
+            _reserved: read_array(r)?,
+            sys_chunk_array: r.array()?,
+            root_backups: read_array(r)?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsStripe {
     pub devid: u64,
@@ -130,7 +295,18 @@ pub struct BtrfsStripe {
     pub dev_uuid: [u8; BTRFS_UUID_SIZE],
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsStripe {
+    const SIZE: usize = 32;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            devid: r.u64()?,
+            offset: r.u64()?,
+            dev_uuid: r.array()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsChunk {
     /// size of this chunk in bytes
@@ -153,14 +329,42 @@ pub struct BtrfsChunk {
     // additional stripes go here
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsChunk {
+    const SIZE: usize = 80;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            length: r.u64()?,
+            owner: r.u64()?,
+            stripe_len: r.u64()?,
+            ty: r.u64()?,
+            io_align: r.u32()?,
+            io_width: r.u32()?,
+            sector_size: r.u32()?,
+            num_stripes: r.u16()?,
+            sub_stripes: r.u16()?,
+            stripe: BtrfsStripe::read(r)?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsTimespec {
     pub sec: u64,
     pub nsec: u32,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsTimespec {
+    const SIZE: usize = 12;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            sec: r.u64()?,
+            nsec: r.u32()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeItem {
     /// nfs style generation number
@@ -185,7 +389,32 @@ pub struct BtrfsInodeItem {
     pub otime: BtrfsTimespec,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsInodeItem {
+    const SIZE: usize = 160;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            generation: r.u64()?,
+            transid: r.u64()?,
+            size: r.u64()?,
+            nbytes: r.u64()?,
+            block_group: r.u64()?,
+            nlink: r.u32()?,
+            uid: r.u32()?,
+            gid: r.u32()?,
+            mode: r.u32()?,
+            rdev: r.u64()?,
+            flags: r.u64()?,
+            sequence: r.u64()?,
+            reserved: read_array(r)?,
+            atime: BtrfsTimespec::read(r)?,
+            ctime: BtrfsTimespec::read(r)?,
+            mtime: BtrfsTimespec::read(r)?,
+            otime: BtrfsTimespec::read(r)?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsRootItem {
     pub inode: BtrfsInodeItem,
@@ -219,7 +448,40 @@ pub struct BtrfsRootItem {
     pub reserved: [u64; 8],
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsRootItem {
+    const SIZE: usize = 439;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            inode: BtrfsInodeItem::read(r)?,
+            generation: r.u64()?,
+            root_dirid: r.u64()?,
+            bytenr: r.u64()?,
+            byte_limit: r.u64()?,
+            bytes_used: r.u64()?,
+            last_snapshot: r.u64()?,
+            flags: r.u64()?,
+            refs: r.u32()?,
+            drop_progress: BtrfsKey::read(r)?,
+            drop_level: r.u8()?,
+            level: r.u8()?,
+            generation_v2: r.u64()?,
+            uuid: r.array()?,
+            parent_uuid: r.array()?,
+            received_uuid: r.array()?,
+            ctransid: r.u64()?,
+            otransid: r.u64()?,
+            stransid: r.u64()?,
+            rtransid: r.u64()?,
+            ctime: BtrfsTimespec::read(r)?,
+            otime: BtrfsTimespec::read(r)?,
+            stime: BtrfsTimespec::read(r)?,
+            rtime: BtrfsTimespec::read(r)?,
+            reserved: read_array(r)?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsDirItem {
     pub location: BtrfsKey,
@@ -229,22 +491,156 @@ pub struct BtrfsDirItem {
     pub ty: u8,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsDirItem {
+    const SIZE: usize = 30;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            location: BtrfsKey::read(r)?,
+            transid: r.u64()?,
+            data_len: r.u16()?,
+            name_len: r.u16()?,
+            ty: r.u8()?,
+        })
+    }
+}
+
+/// Start of an EXTENT_DATA item. Inline extents have their data right after it, other extents
+/// are followed by a `BtrfsFileExtentDisk`.
+#[derive(Copy, Clone)]
+pub struct BtrfsFileExtentItem {
+    /// transaction id that created this extent
+    pub generation: u64,
+    /// size of the extent once decompressed. For inline extents, the size of the inline data.
+    pub ram_bytes: u64,
+    pub compression: u8,
+    pub encryption: u8,
+    pub other_encoding: u16,
+    /// inline, regular or prealloc
+    pub ty: u8,
+}
+
+impl Parse for BtrfsFileExtentItem {
+    const SIZE: usize = 21;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            generation: r.u64()?,
+            ram_bytes: r.u64()?,
+            compression: r.u8()?,
+            encryption: r.u8()?,
+            other_encoding: r.u16()?,
+            ty: r.u8()?,
+        })
+    }
+}
+
+/// Where a regular or prealloc file extent lives
+#[derive(Copy, Clone)]
+pub struct BtrfsFileExtentDisk {
+    /// logical address of the extent on disk, 0 for a hole
+    pub disk_bytenr: u64,
+    pub disk_num_bytes: u64,
+    /// offset into the decompressed extent where the file data starts
+    pub offset: u64,
+    /// number of bytes of the file this extent covers
+    pub num_bytes: u64,
+}
+
+impl Parse for BtrfsFileExtentDisk {
+    const SIZE: usize = 32;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            disk_bytenr: r.u64()?,
+            disk_num_bytes: r.u64()?,
+            offset: r.u64()?,
+            num_bytes: r.u64()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeRef {
     pub index: u64,
     pub name_len: u16,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsInodeRef {
+    const SIZE: usize = 10;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            index: r.u64()?,
+            name_len: r.u16()?,
+        })
+    }
+}
+
+/// Like `BtrfsInodeRef`, but the key offset is a hash of the parent and name, so the parent
+/// is stored here
 #[derive(Copy, Clone)]
+pub struct BtrfsInodeExtref {
+    pub parent_objectid: u64,
+    pub index: u64,
+    pub name_len: u16,
+}
+
+impl Parse for BtrfsInodeExtref {
+    const SIZE: usize = 18;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            parent_objectid: r.u64()?,
+            index: r.u64()?,
+            name_len: r.u16()?,
+        })
+    }
+}
+
+/// Where a subvolume is linked into its parent. ROOT_REF items are keyed (parent, child) and
+/// ROOT_BACKREF items (child, parent), both are followed by the name.
+#[derive(Copy, Clone)]
+pub struct BtrfsRootRef {
+    /// Directory of the parent subvolume holding the link
+    pub dirid: u64,
+    pub sequence: u64,
+    pub name_len: u16,
+}
+
+impl Parse for BtrfsRootRef {
+    const SIZE: usize = 18;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            dirid: r.u64()?,
+            sequence: r.u64()?,
+            name_len: r.u16()?,
+        })
+    }
+}
+
+/// Trees are sorted by objectid, then type, then offset, which is the field order, so the
+/// derived `Ord` is the on-disk key order
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BtrfsKey {
     pub objectid: u64,
     pub ty: u8,
     pub offset: u64,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsKey {
+    const SIZE: usize = 17;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            objectid: r.u64()?,
+            ty: r.u8()?,
+            offset: r.u64()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsHeader {
     pub csum: [u8; BTRFS_CSUM_SIZE],
@@ -259,7 +655,24 @@ pub struct BtrfsHeader {
     pub level: u8,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsHeader {
+    const SIZE: usize = 101;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            csum: r.array()?,
+            fsid: r.array()?,
+            bytenr: r.u64()?,
+            flags: r.u64()?,
+            chunk_tree_uuid: r.array()?,
+            generation: r.u64()?,
+            owner: r.u64()?,
+            nritems: r.u32()?,
+            level: r.u8()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 /// A `BtrfsLeaf` is full of `BtrfsItem`s. `offset` and `size` (relative to start of data area)
 /// tell us where to find the item in the leaf.
@@ -269,14 +682,34 @@ pub struct BtrfsItem {
     pub size: u32,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsItem {
+    const SIZE: usize = 25;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            key: BtrfsKey::read(r)?,
+            offset: r.u32()?,
+            size: r.u32()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsLeaf {
     pub header: BtrfsHeader,
     // `BtrfsItem`s begin here
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsLeaf {
+    const SIZE: usize = 101;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            header: BtrfsHeader::read(r)?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 /// All non-leaf blocks are nodes and they hold only keys are pointers to other blocks
 pub struct BtrfsKeyPtr {
@@ -285,9 +718,30 @@ pub struct BtrfsKeyPtr {
     pub generation: u64,
 }
 
-#[repr(C, packed)]
+impl Parse for BtrfsKeyPtr {
+    const SIZE: usize = 33;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            key: BtrfsKey::read(r)?,
+            blockptr: r.u64()?,
+            generation: r.u64()?,
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BtrfsNode {
     pub header: BtrfsHeader,
     // `BtrfsKeyPtr`s begin here
 }
+
+impl Parse for BtrfsNode {
+    const SIZE: usize = 101;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            header: BtrfsHeader::read(r)?,
+        })
+    }
+}
@@ -0,0 +1,235 @@
+//! Subvolumes and snapshots, found through the ROOT_ITEMs and ROOT_BACKREFs of the root tree
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::{anyhow, Result};
+
+use crate::filesystem::Filesystem;
+use crate::parse::{Parse, Reader};
+use crate::structs::*;
+
+/// A subvolume or snapshot
+#[derive(Debug, Clone)]
+pub struct Subvolume {
+    pub id: u64,
+    /// Id of the subvolume it is linked into, 0 for the top level subvolume
+    pub parent: u64,
+    /// Absolute path of its top directory
+    pub path: OsString,
+    pub uuid: [u8; BTRFS_UUID_SIZE],
+    /// UUID of the subvolume it is a snapshot of, all zeros if it is not a snapshot
+    pub parent_uuid: [u8; BTRFS_UUID_SIZE],
+    /// Transaction it was last changed in
+    pub generation: u64,
+    /// Logical address of its fs tree root node
+    pub root: u64,
+}
+
+/// Parse the payload of a ROOT_ITEM
+pub(crate) fn parse_root_item(data: &[u8]) -> Result<BtrfsRootItem> {
+    // Root items written by old kernels end before `generation_v2`. The missing fields read as
+    // zeros, same as in the kernel.
+    let mut buf = [0; BtrfsRootItem::SIZE];
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+
+    BtrfsRootItem::parse(&buf)
+}
+
+/// Parse the payload of a ROOT_REF or ROOT_BACKREF
+fn parse_root_ref(data: &[u8]) -> Result<(BtrfsRootRef, &[u8])> {
+    let mut reader = Reader::new(data);
+    let root_ref = BtrfsRootRef::read(&mut reader)?;
+    let name = reader.bytes(root_ref.name_len.into())?;
+
+    Ok((root_ref, name))
+}
+
+fn is_subvolume(id: u64) -> bool {
+    id == BTRFS_FS_TREE_OBJECTID
+        || (BTRFS_FIRST_FREE_OBJECTID..=BTRFS_LAST_FREE_OBJECTID).contains(&id)
+}
+
+/// Where a subvolume is linked into its parent
+struct Link {
+    parent: u64,
+    dirid: u64,
+    name: OsString,
+}
+
+impl Filesystem {
+    /// List every subvolume and snapshot, the top level subvolume first, then by id.
+    /// Subvolumes that are not linked anywhere, such as deleted ones waiting to be cleaned up,
+    /// are left out.
+    pub fn subvolumes(&self) -> Result<Vec<Subvolume>> {
+        if let Some(subvolumes) = &*self.subvolume_cache().borrow() {
+            return Ok(subvolumes.clone());
+        }
+
+        let min = BtrfsKey {
+            objectid: BTRFS_FS_TREE_OBJECTID,
+            ty: 0,
+            offset: 0,
+        };
+        let max = BtrfsKey {
+            objectid: BTRFS_LAST_FREE_OBJECTID,
+            ty: u8::MAX,
+            offset: u64::MAX,
+        };
+        let mut roots = BTreeMap::new();
+        let mut links = HashMap::new();
+        for (key, data) in self.root_tree_items(&min, &max)? {
+            if !is_subvolume(key.objectid) {
+                continue;
+            }
+
+            match key.ty {
+                // Keys differ by offset only, the last one is current
+                BTRFS_ROOT_ITEM_KEY => {
+                    roots.insert(key.objectid, parse_root_item(&data)?);
+                }
+                BTRFS_ROOT_BACKREF_KEY | BTRFS_ROOT_REF_KEY => {
+                    let (root_ref, name) = parse_root_ref(&data)?;
+                    let (child, parent) = if key.ty == BTRFS_ROOT_BACKREF_KEY {
+                        (key.objectid, key.offset)
+                    } else {
+                        (key.offset, key.objectid)
+                    };
+                    links.entry(child).or_insert(Link {
+                        parent,
+                        dirid: root_ref.dirid,
+                        name: OsStr::from_bytes(name).to_os_string(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let mut paths = HashMap::new();
+        let mut subvolumes = Vec::new();
+        for (id, root_item) in &roots {
+            let path =
+                match self.subvolume_path(*id, &roots, &links, &mut paths, &mut HashSet::new())? {
+                    Some(path) => path,
+                    None => continue,
+                };
+            subvolumes.push(Subvolume {
+                id: *id,
+                parent: links.get(id).map_or(0, |link| link.parent),
+                path,
+                uuid: root_item.uuid,
+                parent_uuid: root_item.parent_uuid,
+                generation: root_item.generation,
+                root: root_item.bytenr,
+            });
+        }
+
+        *self.subvolume_cache().borrow_mut() = Some(subvolumes.clone());
+
+        Ok(subvolumes)
+    }
+
+    /// Handle on subvolume `id`, to read files from it
+    pub fn subvolume(&self, id: u64) -> Result<Filesystem> {
+        if id == self.subvol_id() {
+            return Ok(self.clone());
+        }
+
+        let subvolume = self
+            .subvolumes()?
+            .into_iter()
+            .find(|subvolume| subvolume.id == id)
+            .ok_or_else(|| anyhow!("subvolume id={} not found", id))?;
+        let path = if id == BTRFS_FS_TREE_OBJECTID {
+            OsString::new()
+        } else {
+            subvolume.path
+        };
+
+        Ok(self.subvolume_handle(id, subvolume.root, path))
+    }
+
+    /// Compute the absolute path of subvolume `id`, or `None` if it is not reachable from the
+    /// top level subvolume. `paths` memoizes results, `seen` guards against link loops.
+    fn subvolume_path(
+        &self,
+        id: u64,
+        roots: &BTreeMap<u64, BtrfsRootItem>,
+        links: &HashMap<u64, Link>,
+        paths: &mut HashMap<u64, Option<OsString>>,
+        seen: &mut HashSet<u64>,
+    ) -> Result<Option<OsString>> {
+        if id == BTRFS_FS_TREE_OBJECTID {
+            return Ok(Some("/".into()));
+        }
+        if let Some(path) = paths.get(&id) {
+            return Ok(path.clone());
+        }
+        let link = match links.get(&id) {
+            Some(link) => link,
+            None => return Ok(None),
+        };
+        if !seen.insert(id) {
+            self.warn(format!("subvolume id={} is linked into itself", id));
+            return Ok(None);
+        }
+
+        let path = match roots.get(&link.parent) {
+            Some(parent_root) => {
+                match self.subvolume_path(link.parent, roots, links, paths, seen)? {
+                    Some(parent_path) => {
+                        // The handle's path is empty for the top level subvolume
+                        let parent_path = match parent_path.as_bytes() {
+                            b"/" => OsString::new(),
+                            _ => parent_path,
+                        };
+                        let parent =
+                            self.subvolume_handle(link.parent, parent_root.bytenr, parent_path);
+                        let mut path = parent.dir_path(link.dirid)?;
+                        path.push(&link.name);
+                        Some(path)
+                    }
+                    None => None,
+                }
+            }
+            None => {
+                self.warn(format!(
+                    "subvolume id={} is linked into missing subvolume id={}",
+                    id, link.parent
+                ));
+                None
+            }
+        };
+        paths.insert(id, path.clone());
+
+        Ok(path)
+    }
+}
+
+#[test]
+fn test_parse_root_ref() {
+    let mut data = Vec::new();
+    data.extend_from_slice(&256u64.to_le_bytes()); // dirid
+    data.extend_from_slice(&3u64.to_le_bytes()); // sequence
+    data.extend_from_slice(&4u16.to_le_bytes());
+    data.extend_from_slice(b"snap");
+
+    let (root_ref, name) = parse_root_ref(&data).unwrap();
+    assert_eq!(root_ref.dirid, 256);
+    assert_eq!(root_ref.sequence, 3);
+    assert_eq!(name, b"snap");
+
+    assert!(parse_root_ref(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_parse_root_item() {
+    // Old root items end before `generation_v2`
+    let mut data = vec![0; 239];
+    data[160] = 7; // generation
+    let root_item = parse_root_item(&data).unwrap();
+    assert_eq!(root_item.generation, 7);
+    assert_eq!(root_item.uuid, [0; BTRFS_UUID_SIZE]);
+}
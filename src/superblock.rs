@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::prelude::FileExt;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::csum::{self, CsumType};
+use crate::parse::Parse;
+use crate::structs::*;
+
+/// Physical addresses of the primary superblock and its two mirrors. Mirrors that don't fit
+/// on the device are not written.
+pub const BTRFS_SUPERBLOCK_OFFSETS: [u64; 3] = [0x10_000, 0x400_0000, 0x40_0000_0000];
+const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
+/// Size of the on-disk superblock, including padding. The checksum covers all of it.
+const BTRFS_SUPER_INFO_SIZE: usize = 4096;
+
+/// A chunk tree root and root tree root that were committed together
+#[derive(Debug, Clone)]
+pub struct TreeRoots {
+    /// Where these roots came from, for diagnostics
+    pub source: String,
+    pub chunk_root: u64,
+    pub chunk_root_generation: u64,
+    pub root: u64,
+    pub root_generation: u64,
+}
+
+/// Read and validate the superblock copy at physical address `offset`
+fn parse_superblock_at(file: &File, offset: u64) -> Result<BtrfsSuperblock> {
+    let mut buf = vec![0; BTRFS_SUPER_INFO_SIZE];
+    file.read_exact_at(&mut buf, offset)?;
+
+    let superblock = BtrfsSuperblock::parse(&buf)?;
+    if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
+        bail!("superblock magic is wrong");
+    }
+
+    let csum_type = CsumType::from_raw(superblock.csum_type)?;
+    csum::verify(csum_type, &buf, "superblock", offset, offset)?;
+
+    if superblock.bytenr != offset {
+        bail!(
+            "superblock at offset={} claims to be at bytenr={}",
+            offset,
+            superblock.bytenr
+        );
+    }
+
+    Ok(superblock)
+}
+
+/// Read the superblock copy selected by `mirror`, or if `None`, the valid copy with the highest
+/// generation. Invalid copies are described in `warnings`.
+pub(crate) fn parse_superblock(
+    file: &File,
+    mirror: Option<usize>,
+    warnings: &mut Vec<String>,
+) -> Result<BtrfsSuperblock> {
+    let device_size = (&*file).seek(SeekFrom::End(0))?;
+
+    if let Some(mirror) = mirror {
+        let offset = *BTRFS_SUPERBLOCK_OFFSETS
+            .get(mirror)
+            .ok_or_else(|| anyhow!("superblock mirror={} does not exist", mirror))?;
+        if offset + BTRFS_SUPER_INFO_SIZE as u64 > device_size {
+            bail!("superblock mirror={} is past the end of the device", mirror);
+        }
+
+        return parse_superblock_at(file, offset);
+    }
+
+    let mut best: Option<BtrfsSuperblock> = None;
+    for (mirror, offset) in BTRFS_SUPERBLOCK_OFFSETS.iter().enumerate() {
+        if offset + BTRFS_SUPER_INFO_SIZE as u64 > device_size {
+            break;
+        }
+
+        match parse_superblock_at(file, *offset) {
+            Ok(superblock) => {
+                if best.is_none_or(|b| superblock.generation > b.generation) {
+                    best = Some(superblock);
+                }
+            }
+            Err(e) => warnings.push(format!("superblock mirror={} is invalid: {}", mirror, e)),
+        }
+    }
+
+    best.ok_or_else(|| anyhow!("no valid superblock found"))
+}
+
+/// Tree roots to try in order: the ones in the superblock followed by the backup roots from
+/// newest to oldest, same as `mount -o usebackuproot`. If `backup` is set, only that backup slot
+/// is returned.
+pub(crate) fn tree_roots_candidates(
+    superblock: &BtrfsSuperblock,
+    backup: Option<usize>,
+) -> Result<Vec<TreeRoots>> {
+    let backup_roots = |slot: usize, backup: &BtrfsRootBackup| TreeRoots {
+        source: format!("backup root slot={}", slot),
+        chunk_root: backup.chunk_root,
+        chunk_root_generation: backup.chunk_root_gen,
+        root: backup.tree_root,
+        root_generation: backup.tree_root_gen,
+    };
+
+    let root_backups = &superblock.root_backups;
+    if let Some(slot) = backup {
+        let backup = root_backups
+            .get(slot)
+            .ok_or_else(|| anyhow!("backup root slot={} does not exist", slot))?;
+        if backup.tree_root == 0 {
+            bail!("backup root slot={} is empty", slot);
+        }
+
+        return Ok(vec![backup_roots(slot, backup)]);
+    }
+
+    let mut candidates = vec![TreeRoots {
+        source: "superblock".to_string(),
+        chunk_root: superblock.chunk_root,
+        chunk_root_generation: superblock.chunk_root_generation,
+        root: superblock.root,
+        root_generation: superblock.generation,
+    }];
+
+    let mut backups: Vec<_> = root_backups
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.tree_root != 0)
+        .collect();
+    backups.sort_by_key(|(_, b)| std::cmp::Reverse(b.tree_root_gen));
+    candidates.extend(backups.into_iter().map(|(slot, b)| backup_roots(slot, b)));
+
+    Ok(candidates)
+}
@@ -1,41 +1,737 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
 use anyhow::{bail, Result};
 
+use crate::parse::{Parse, Reader};
 use crate::structs::*;
 
+/// Trees never have more levels than this
+pub const BTRFS_MAX_LEVEL: u8 = 8;
+
 /// Parse BtrfsHeader from a tree node (internal or leaf)
-pub fn parse_btrfs_header<'a>(buf: &'a [u8]) -> Result<&'a BtrfsHeader> {
-    let header_size = std::mem::size_of::<BtrfsHeader>();
-    if buf.len() < header_size {
+pub fn parse_btrfs_header(buf: &[u8]) -> Result<BtrfsHeader> {
+    if buf.len() < BtrfsHeader::SIZE {
         bail!("Failed to parse BtrfsHeader b/c buf too small");
     }
 
-    Ok(unsafe { &*(buf.as_ptr() as *const BtrfsHeader) })
+    let header = BtrfsHeader::parse(buf)?;
+    if header.level >= BTRFS_MAX_LEVEL {
+        bail!(
+            "tree node at bytenr={} has invalid level={}",
+            header.bytenr,
+            header.level
+        );
+    }
+
+    Ok(header)
+}
+
+/// Name of the tree with objectid `owner`, as tree block headers give it. Subvolumes go by
+/// their id.
+pub fn tree_name(owner: u64) -> String {
+    match owner {
+        1 => "root",
+        2 => "extent",
+        3 => "chunk",
+        4 => "dev",
+        5 => "fs",
+        7 => "csum",
+        8 => "quota",
+        9 => "uuid",
+        10 => "free-space",
+        11 => "block-group",
+        _ => return owner.to_string(),
+    }
+    .to_string()
+}
+
+/// Make sure `child` sits exactly one level below the node at `parent_level` that points to it.
+/// Otherwise a pointer loop in a corrupted tree could send a walk around in circles.
+pub fn check_child_level(parent_level: u8, child: &[u8]) -> Result<()> {
+    let header = parse_btrfs_header(child)?;
+    if header.level + 1 != parent_level {
+        bail!(
+            "tree node at bytenr={} has level={} but its parent has level={}",
+            header.bytenr,
+            header.level,
+            parent_level
+        );
+    }
+
+    Ok(())
 }
 
 /// Parse an internal tree node
 ///
 /// Precondition is that `buf` is not a leaf node.
-pub fn parse_btrfs_node<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsKeyPtr>> {
+pub fn parse_btrfs_node(buf: &[u8]) -> Result<Vec<BtrfsKeyPtr>> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
+    let mut reader = Reader::new(&buf[BtrfsHeader::SIZE..]);
     let mut key_ptrs = Vec::new();
     for _ in 0..header.nritems {
-        key_ptrs.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsKeyPtr) });
-        offset += std::mem::size_of::<BtrfsKeyPtr>();
+        key_ptrs.push(BtrfsKeyPtr::read(&mut reader)?);
     }
 
     Ok(key_ptrs)
 }
 
 /// Parse leaf tree node
-pub fn parse_btrfs_leaf<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsItem>> {
+pub fn parse_btrfs_leaf(buf: &[u8]) -> Result<Vec<BtrfsItem>> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
+    let mut reader = Reader::new(&buf[BtrfsHeader::SIZE..]);
     let mut items = Vec::new();
     for _ in 0..header.nritems {
-        items.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsItem) });
-        offset += std::mem::size_of::<BtrfsItem>();
+        items.push(BtrfsItem::read(&mut reader)?);
+    }
+
+    Ok(items)
+}
+
+/// Returns the payload of `item` in leaf `buf`
+pub fn item_data<'a>(buf: &'a [u8], item: &BtrfsItem) -> Result<&'a [u8]> {
+    // `item.offset` is relative to the end of the header
+    let start = BtrfsHeader::SIZE + item.offset as usize;
+    let end = start + item.size as usize;
+    if end > buf.len() {
+        bail!(
+            "item data at offset={} size={} is outside the {} byte leaf",
+            item.offset,
+            item.size,
+            buf.len()
+        );
+    }
+
+    Ok(&buf[start..end])
+}
+
+/// Reads and validates the tree block at a logical address
+pub trait ReadBlock: FnMut(u64) -> Result<Rc<[u8]>> {}
+
+impl<F: FnMut(u64) -> Result<Rc<[u8]>>> ReadBlock for F {}
+
+/// Find the item with key `key` in the tree rooted at the block at `root`, reading blocks with
+/// `read_block`. Only the blocks on the path from the root to the leaf that would hold the key
+/// are read, and only the keys a binary search visits are decoded.
+pub fn search<F>(root: u64, key: &BtrfsKey, mut read_block: F) -> Result<Option<Vec<u8>>>
+where
+    F: ReadBlock,
+{
+    let mut block = read_block(root)?;
+    loop {
+        let header = parse_btrfs_header(&block)?;
+        let nritems = header.nritems as usize;
+
+        // Leaf node
+        if header.level == 0 {
+            let slot = match binary_search(nritems, key, |slot| {
+                Ok(slot_entry::<BtrfsItem>(&block, slot)?.key)
+            })? {
+                Ok(slot) => slot,
+                Err(_) => return Ok(None),
+            };
+            let item = slot_entry::<BtrfsItem>(&block, slot)?;
+
+            return Ok(Some(item_data(&block, &item)?.to_vec()));
+        }
+
+        // Descend into the last child that starts at or before `key`, like `Cursor::seek`
+        let slot = match binary_search(nritems, key, |slot| {
+            Ok(slot_entry::<BtrfsKeyPtr>(&block, slot)?.key)
+        })? {
+            Ok(slot) => slot,
+            // Before the first key of the tree
+            Err(0) => return Ok(None),
+            Err(slot) => slot - 1,
+        };
+        let child = read_block(slot_entry::<BtrfsKeyPtr>(&block, slot)?.blockptr)?;
+        check_child_level(header.level, &child)?;
+        block = child;
+    }
+}
+
+/// Binary search the `nritems` sorted keys that `key_at` decodes for `key`. Returns the slot of
+/// `key` if found, otherwise the slot it would be inserted at, like `slice::binary_search`.
+fn binary_search<K>(nritems: usize, key: &BtrfsKey, key_at: K) -> Result<Result<usize, usize>>
+where
+    K: Fn(usize) -> Result<BtrfsKey>,
+{
+    let (mut lo, mut hi) = (0, nritems);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match key_at(mid)?.cmp(key) {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Greater => hi = mid,
+            Ordering::Equal => return Ok(Ok(mid)),
+        }
+    }
+
+    Ok(Err(lo))
+}
+
+/// Decode the item header or key pointer at `slot` of `block`, without parsing the others
+fn slot_entry<T: Parse>(block: &[u8], slot: usize) -> Result<T> {
+    let start = BtrfsHeader::SIZE + slot * T::SIZE;
+    match block.get(start..) {
+        Some(buf) => T::parse(buf),
+        None => bail!("slot={} is outside the {} byte block", slot, block.len()),
+    }
+}
+
+/// Collect the keys and payloads of the items with keys from `min` up to and including `max`,
+/// in key order, from the tree rooted at the block at `root`
+pub fn search_range<F>(
+    root: u64,
+    min: &BtrfsKey,
+    max: &BtrfsKey,
+    read_block: F,
+) -> Result<Vec<(BtrfsKey, Vec<u8>)>>
+where
+    F: ReadBlock,
+{
+    let mut items = Vec::new();
+    for item in Cursor::new(root, min, read_block)? {
+        let item = item?;
+        if item.0 > *max {
+            break;
+        }
+        items.push(item);
     }
 
     Ok(items)
 }
+
+/// A position between two items of a tree. `next` returns the item after it and `prev` the one
+/// before it, moving to neighbouring leaves as needed. Blocks are read with `read_block`, only
+/// when the cursor gets to them.
+pub struct Cursor<F> {
+    root: u64,
+    read_block: F,
+    /// Nodes from the root down to the current leaf
+    path: Vec<PathNode>,
+}
+
+/// A block on the path of a `Cursor`, decoded once when the cursor gets to it so each step
+/// only costs a lookup
+struct PathNode {
+    block: Rc<[u8]>,
+    header: BtrfsHeader,
+    /// Item headers of a leaf, empty for internal nodes
+    items: Vec<BtrfsItem>,
+    /// Child pointers of an internal node, empty for leaves
+    ptrs: Vec<BtrfsKeyPtr>,
+    /// For internal nodes the child the path goes through, for the leaf the item after the
+    /// position
+    slot: usize,
+}
+
+impl PathNode {
+    fn new(block: Rc<[u8]>) -> Result<Self> {
+        let header = parse_btrfs_header(&block)?;
+        let (items, ptrs) = if header.level == 0 {
+            (parse_btrfs_leaf(&block)?, Vec::new())
+        } else {
+            (Vec::new(), parse_btrfs_node(&block)?)
+        };
+
+        Ok(Self {
+            block,
+            header,
+            items,
+            ptrs,
+            slot: 0,
+        })
+    }
+
+    fn nritems(&self) -> usize {
+        if self.header.level == 0 {
+            self.items.len()
+        } else {
+            self.ptrs.len()
+        }
+    }
+
+    /// Key and payload of item `slot` of a leaf
+    fn item(&self, slot: usize) -> Result<(BtrfsKey, Vec<u8>)> {
+        let item = &self.items[slot];
+
+        Ok((item.key, item_data(&self.block, item)?.to_vec()))
+    }
+}
+
+impl<F> Cursor<F>
+where
+    F: ReadBlock,
+{
+    /// Create a cursor positioned before the first item with a key >= `key` in the tree
+    /// rooted at the block at `root`
+    pub fn new(root: u64, key: &BtrfsKey, read_block: F) -> Result<Self> {
+        let mut cursor = Self {
+            root,
+            read_block,
+            path: Vec::new(),
+        };
+        cursor.seek(key)?;
+
+        Ok(cursor)
+    }
+
+    /// Move before the first item with a key >= `key`
+    pub fn seek(&mut self, key: &BtrfsKey) -> Result<()> {
+        self.path.clear();
+        let mut node = PathNode::new((self.read_block)(self.root)?)?;
+        loop {
+            // Leaf node
+            if node.header.level == 0 {
+                node.slot = node.items.partition_point(|item| item.key < *key);
+                self.path.push(node);
+                return Ok(());
+            }
+
+            // Child i holds keys from ptrs[i].key up to ptrs[i + 1].key, so descend into the
+            // last child that starts at or before `key`
+            node.slot = node
+                .ptrs
+                .partition_point(|ptr| ptr.key <= *key)
+                .saturating_sub(1);
+            let child = read_child(&mut self.read_block, &node)?;
+            self.path.push(node);
+            node = child;
+        }
+    }
+
+    /// Returns the item before the position and moves the position back over it
+    pub fn prev(&mut self) -> Option<Result<(BtrfsKey, Vec<u8>)>> {
+        let ret = self.prev_item().transpose();
+        if let Some(Err(_)) = ret {
+            self.path.clear();
+        }
+
+        ret
+    }
+
+    fn next_item(&mut self) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        loop {
+            let leaf = match self.path.last_mut() {
+                Some(leaf) => leaf,
+                None => return Ok(None),
+            };
+            if leaf.slot < leaf.items.len() {
+                leaf.slot += 1;
+                return leaf.item(leaf.slot - 1).map(Some);
+            }
+
+            if !self.step(true)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn prev_item(&mut self) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        loop {
+            let leaf = match self.path.last_mut() {
+                Some(leaf) => leaf,
+                None => return Ok(None),
+            };
+            if leaf.slot > 0 {
+                leaf.slot -= 1;
+                return leaf.item(leaf.slot).map(Some);
+            }
+
+            if !self.step(false)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Move to the start of the next leaf, or the end of the previous one. Returns false, and
+    /// stays put, if there is no such leaf.
+    fn step(&mut self, forward: bool) -> Result<bool> {
+        // Deepest internal node with a sibling of the current child in that direction
+        let mut level = self.path.len() - 1;
+        loop {
+            if level == 0 {
+                return Ok(false);
+            }
+            level -= 1;
+
+            let node = &self.path[level];
+            if forward && node.slot + 1 < node.nritems() || !forward && node.slot > 0 {
+                break;
+            }
+        }
+
+        self.path.truncate(level + 1);
+        let node = self.path.last_mut().unwrap();
+        if forward {
+            node.slot += 1;
+        } else {
+            node.slot -= 1;
+        }
+        let mut node = read_child(&mut self.read_block, node)?;
+
+        // Down the leftmost or rightmost children to a leaf
+        loop {
+            node.slot = if forward {
+                0
+            } else if node.header.level == 0 {
+                node.nritems()
+            } else {
+                node.nritems().saturating_sub(1)
+            };
+            if node.header.level == 0 {
+                self.path.push(node);
+                return Ok(true);
+            }
+
+            let child = read_child(&mut self.read_block, &node)?;
+            self.path.push(node);
+            node = child;
+        }
+    }
+}
+
+/// Items after the position, in key order. The cursor stops after the first error.
+impl<F> Iterator for Cursor<F>
+where
+    F: ReadBlock,
+{
+    type Item = Result<(BtrfsKey, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = self.next_item().transpose();
+        if let Some(Err(_)) = ret {
+            self.path.clear();
+        }
+
+        ret
+    }
+}
+
+/// Read the child of internal node `node` at its current slot
+fn read_child<F>(read_block: &mut F, node: &PathNode) -> Result<PathNode>
+where
+    F: ReadBlock,
+{
+    let ptr = match node.ptrs.get(node.slot) {
+        Some(ptr) => *ptr,
+        None => bail!(
+            "tree node at bytenr={} has no child at slot={}",
+            node.header.bytenr,
+            node.slot
+        ),
+    };
+    let child = read_block(ptr.blockptr)?;
+    check_child_level(node.header.level, &child)?;
+
+    PathNode::new(child)
+}
+
+/// One record of a DIR_ITEM, DIR_INDEX or XATTR_ITEM item
+pub struct DirItemEntry<'a> {
+    pub dir_item: BtrfsDirItem,
+    pub name: &'a [u8],
+    /// Xattr value, empty for directory entries
+    pub data: &'a [u8],
+}
+
+/// Hash of a directory entry name, the key offset of its DIR_ITEM
+pub fn name_hash(name: &[u8]) -> u64 {
+    // The kernel hashes with crc32c(~1, name), whose seed and result are not inverted the way
+    // `crc32c_append` inverts them
+    u64::from(!crc32c::crc32c_append(1, name))
+}
+
+/// Decode every record packed in the payload of a DIR_ITEM, DIR_INDEX or XATTR_ITEM item.
+/// Names whose hashes collide share one item, one record after another.
+pub fn parse_dir_items(data: &[u8]) -> Result<Vec<DirItemEntry<'_>>> {
+    let mut reader = Reader::new(data);
+    let mut entries = Vec::new();
+    while reader.remaining() > 0 {
+        let dir_item = BtrfsDirItem::read(&mut reader)?;
+        let name = reader.bytes(dir_item.name_len.into())?;
+        let data = reader.bytes(dir_item.data_len.into())?;
+        entries.push(DirItemEntry {
+            dir_item,
+            name,
+            data,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Decode every (ref, name) pair packed in the payload of an INODE_REF item. An inode with
+/// several names in the same directory has one ref for each in the same item.
+pub fn parse_inode_refs(data: &[u8]) -> Result<Vec<(BtrfsInodeRef, &[u8])>> {
+    let mut reader = Reader::new(data);
+    let mut refs = Vec::new();
+    while reader.remaining() > 0 {
+        let inode_ref = BtrfsInodeRef::read(&mut reader)?;
+        let name = reader.bytes(inode_ref.name_len.into())?;
+        refs.push((inode_ref, name));
+    }
+
+    Ok(refs)
+}
+
+/// Decode every (extref, name) pair packed in the payload of an INODE_EXTREF item. Names whose
+/// hashes collide share one item.
+pub fn parse_inode_extrefs(data: &[u8]) -> Result<Vec<(BtrfsInodeExtref, &[u8])>> {
+    let mut reader = Reader::new(data);
+    let mut extrefs = Vec::new();
+    while reader.remaining() > 0 {
+        let extref = BtrfsInodeExtref::read(&mut reader)?;
+        let name = reader.bytes(extref.name_len.into())?;
+        extrefs.push((extref, name));
+    }
+
+    Ok(extrefs)
+}
+
+#[test]
+fn test_parse_dir_items() {
+    let mut data = Vec::new();
+    for (name, value) in [(&b"first"[..], &b""[..]), (b"second", b"xy")] {
+        data.extend_from_slice(&[0; BtrfsKey::SIZE + 8]); // location, transid
+        data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.push(BTRFS_FT_REG_FILE);
+        data.extend_from_slice(name);
+        data.extend_from_slice(value);
+    }
+
+    let entries = parse_dir_items(&data).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, b"first");
+    assert_eq!(entries[1].name, b"second");
+    assert_eq!(entries[1].data, b"xy");
+
+    // Name runs past the end of the item
+    assert!(parse_dir_items(&data[..data.len() - 3]).is_err());
+}
+
+#[test]
+fn test_parse_inode_refs() {
+    let mut data = Vec::new();
+    for (index, name) in [(2u64, &b"a"[..]), (3, b"link")] {
+        data.extend_from_slice(&index.to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(name);
+    }
+
+    let refs = parse_inode_refs(&data).unwrap();
+    assert_eq!(refs.len(), 2);
+    assert_eq!((refs[0].0.index, refs[0].1), (2, &b"a"[..]));
+    assert_eq!((refs[1].0.index, refs[1].1), (3, &b"link"[..]));
+
+    assert!(parse_inode_refs(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_parse_inode_extrefs() {
+    let mut data = Vec::new();
+    data.extend_from_slice(&300u64.to_le_bytes());
+    data.extend_from_slice(&7u64.to_le_bytes());
+    data.extend_from_slice(&4u16.to_le_bytes());
+    data.extend_from_slice(b"name");
+
+    let extrefs = parse_inode_extrefs(&data).unwrap();
+    assert_eq!(extrefs.len(), 1);
+    assert_eq!(extrefs[0].0.parent_objectid, 300);
+    assert_eq!(extrefs[0].0.index, 7);
+    assert_eq!(extrefs[0].1, b"name");
+
+    assert!(parse_inode_extrefs(&data[..BtrfsInodeExtref::SIZE - 1]).is_err());
+}
+
+#[cfg(test)]
+fn test_block(bytenr: u64, level: u8, nritems: usize) -> Vec<u8> {
+    let mut block = vec![0; 1024];
+    block[48..56].copy_from_slice(&bytenr.to_le_bytes());
+    block[96..100].copy_from_slice(&(nritems as u32).to_le_bytes());
+    block[100] = level;
+    block
+}
+
+#[cfg(test)]
+fn write_key(buf: &mut [u8], key: &BtrfsKey) {
+    buf[..8].copy_from_slice(&key.objectid.to_le_bytes());
+    buf[8] = key.ty;
+    buf[9..17].copy_from_slice(&key.offset.to_le_bytes());
+}
+
+/// A leaf at `bytenr` holding `items`, with their payloads packed at the end of the block
+#[cfg(test)]
+pub(crate) fn test_leaf(bytenr: u64, items: &[(BtrfsKey, Vec<u8>)]) -> Vec<u8> {
+    let mut leaf = test_block(bytenr, 0, items.len());
+    let mut end = leaf.len();
+    for (i, (key, data)) in items.iter().enumerate() {
+        let item = BtrfsHeader::SIZE + i * BtrfsItem::SIZE;
+        end -= data.len();
+        write_key(&mut leaf[item..], key);
+        let offset = (end - BtrfsHeader::SIZE) as u32;
+        leaf[item + 17..item + 21].copy_from_slice(&offset.to_le_bytes());
+        leaf[item + 21..item + 25].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        leaf[end..end + data.len()].copy_from_slice(data);
+    }
+    leaf
+}
+
+/// An internal node at `bytenr` pointing to the blocks in `ptrs`, each with its first key
+#[cfg(test)]
+pub(crate) fn test_node(bytenr: u64, level: u8, ptrs: &[(BtrfsKey, u64)]) -> Vec<u8> {
+    let mut node = test_block(bytenr, level, ptrs.len());
+    for (i, (key, blockptr)) in ptrs.iter().enumerate() {
+        let ptr = BtrfsHeader::SIZE + i * BtrfsKeyPtr::SIZE;
+        write_key(&mut node[ptr..], key);
+        node[ptr + 17..ptr + 25].copy_from_slice(&blockptr.to_le_bytes());
+    }
+    node
+}
+
+/// Two leaves holding objectids 0-9 with type 1 and 2 each, under a root node at 4096. Each
+/// item's payload is its objectid and type.
+#[cfg(test)]
+fn test_tree() -> std::collections::HashMap<u64, Vec<u8>> {
+    let mut blocks = std::collections::HashMap::new();
+    let mut ptrs = Vec::new();
+    for (i, bytenr) in [8192u64, 12288].iter().enumerate() {
+        let items: Vec<_> = (i as u64 * 5..i as u64 * 5 + 5)
+            .flat_map(|objectid| {
+                [1, 2].map(|ty| (test_key(objectid, ty), vec![objectid as u8, ty]))
+            })
+            .collect();
+        ptrs.push((items[0].0, *bytenr));
+        blocks.insert(*bytenr, test_leaf(*bytenr, &items));
+    }
+    blocks.insert(4096, test_node(4096, 1, &ptrs));
+
+    blocks
+}
+
+#[cfg(test)]
+pub(crate) fn test_key(objectid: u64, ty: u8) -> BtrfsKey {
+    BtrfsKey {
+        objectid,
+        ty,
+        offset: 0,
+    }
+}
+
+#[test]
+fn test_search() {
+    let blocks = test_tree();
+    let reads = std::cell::RefCell::new(Vec::new());
+    let read_block = |bytenr: u64| {
+        reads.borrow_mut().push(bytenr);
+        Ok(blocks[&bytenr].clone().into())
+    };
+    assert_eq!(
+        search(4096, &test_key(7, 2), read_block).unwrap(),
+        Some(vec![7, 2])
+    );
+    assert_eq!(search(4096, &test_key(7, 3), read_block).unwrap(), None);
+    assert_eq!(search(4096, &test_key(11, 1), read_block).unwrap(), None);
+    // Only the root and the leaf holding the key were read
+    assert_eq!(*reads.borrow(), [4096, 12288, 4096, 12288, 4096, 12288]);
+
+    // The last item of the first leaf, and a key after it, never go on to the second leaf
+    reads.borrow_mut().clear();
+    assert_eq!(
+        search(4096, &test_key(4, 2), read_block).unwrap(),
+        Some(vec![4, 2])
+    );
+    assert_eq!(search(4096, &test_key(4, 3), read_block).unwrap(), None);
+    assert_eq!(*reads.borrow(), [4096, 8192, 4096, 8192]);
+
+    // A key before the first one is missing without reading a leaf
+    reads.borrow_mut().clear();
+    let first = BtrfsKey {
+        objectid: 0,
+        ty: 0,
+        offset: 0,
+    };
+    assert_eq!(search(4096, &first, read_block).unwrap(), None);
+    assert_eq!(*reads.borrow(), [4096]);
+
+    // A range across both leaves
+    let items = search_range(4096, &test_key(4, 2), &test_key(5, 1), |bytenr| {
+        Ok(blocks[&bytenr].clone().into())
+    })
+    .unwrap();
+    let keys: Vec<_> = items
+        .iter()
+        .map(|(key, _)| (key.objectid, key.ty))
+        .collect();
+    assert_eq!(keys, [(4, 2), (5, 1)]);
+}
+
+#[test]
+fn test_cursor() {
+    let blocks = test_tree();
+    let read_block = |bytenr: u64| Ok(blocks[&bytenr].clone().into());
+    let key = |item: Option<Result<(BtrfsKey, Vec<u8>)>>| {
+        item.map(|item| {
+            let (key, data) = item.unwrap();
+            assert_eq!(data, [key.objectid as u8, key.ty]);
+            (key.objectid, key.ty)
+        })
+    };
+
+    // Forward and back across the leaf boundary
+    let mut cursor = Cursor::new(4096, &test_key(4, 1), read_block).unwrap();
+    assert_eq!(key(cursor.next()), Some((4, 1)));
+    assert_eq!(key(cursor.next()), Some((4, 2)));
+    assert_eq!(key(cursor.next()), Some((5, 1)));
+    assert_eq!(key(cursor.prev()), Some((5, 1)));
+    assert_eq!(key(cursor.prev()), Some((4, 2)));
+    assert_eq!(key(cursor.prev()), Some((4, 1)));
+
+    // Both ends
+    cursor.seek(&test_key(100, 0)).unwrap();
+    assert_eq!(key(cursor.next()), None);
+    assert_eq!(key(cursor.prev()), Some((9, 2)));
+    cursor.seek(&test_key(0, 0)).unwrap();
+    assert_eq!(key(cursor.prev()), None);
+    assert_eq!(cursor.count(), 20);
+}
+
+#[test]
+fn test_name_hash() {
+    // Key offset of the "default" DIR_ITEM in the root tree of every filesystem
+    assert_eq!(name_hash(b"default"), 0x8dbfc2d2);
+}
+
+#[test]
+fn test_parse_leaf_bounds() {
+    let mut leaf = vec![0; 200];
+    // nritems
+    leaf[96..100].copy_from_slice(&1u32.to_le_bytes());
+    // key objectid=256, type=1, offset=0, then item offset and size
+    leaf[101..109].copy_from_slice(&256u64.to_le_bytes());
+    leaf[109] = 1;
+    leaf[118..122].copy_from_slice(&50u32.to_le_bytes());
+    leaf[122..126].copy_from_slice(&10u32.to_le_bytes());
+
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].key.objectid, 256);
+    assert_eq!(item_data(&leaf, &items[0]).unwrap().len(), 10);
+
+    // Item data runs off the end of the leaf
+    leaf[118..122].copy_from_slice(&95u32.to_le_bytes());
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    assert!(item_data(&leaf, &items[0]).is_err());
+
+    // More items claimed than fit in the leaf
+    leaf[96..100].copy_from_slice(&100u32.to_le_bytes());
+    assert!(parse_btrfs_leaf(&leaf).is_err());
+}
+
+#[test]
+fn test_tree_name() {
+    assert_eq!(tree_name(BTRFS_FS_TREE_OBJECTID), "fs");
+    assert_eq!(tree_name(3), "chunk");
+    assert_eq!(tree_name(257), "257");
+}
@@ -0,0 +1,312 @@
+//! Extended attributes, from the XATTR_ITEMs of an inode, and readable forms of the values
+//! the kernel gives a meaning to: POSIX ACLs, file capabilities and security labels
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::{bail, Result};
+
+use crate::filesystem::Filesystem;
+use crate::parse::Reader;
+use crate::structs::*;
+use crate::tree;
+
+pub const XATTR_NAME_POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+pub const XATTR_NAME_POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+pub const XATTR_NAME_CAPS: &str = "security.capability";
+pub const XATTR_NAME_SELINUX: &str = "security.selinux";
+
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff000000;
+const VFS_CAP_REVISION_1: u32 = 0x01000000;
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+const VFS_CAP_REVISION_3: u32 = 0x03000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+/// Capability names by bit number, as `getcap` prints them
+const CAP_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// An extended attribute of an inode
+#[derive(Debug, Clone)]
+pub struct Xattr {
+    /// Raw bytes, which need not be UTF-8
+    pub name: OsString,
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    /// Readable form of the value of a POSIX ACL, file capability or SELinux label. `None` for
+    /// other attributes.
+    pub fn decode(&self) -> Option<Result<String>> {
+        Some(match self.name.to_str()? {
+            XATTR_NAME_POSIX_ACL_ACCESS | XATTR_NAME_POSIX_ACL_DEFAULT => decode_acl(&self.value),
+            XATTR_NAME_CAPS => decode_caps(&self.value),
+            XATTR_NAME_SELINUX => decode_label(&self.value),
+            _ => return None,
+        })
+    }
+
+    /// The value as `getfattr` would show it: decoded if possible, else quoted if it is text,
+    /// else in hex
+    pub fn display_value(&self) -> String {
+        match self.decode() {
+            Some(Ok(decoded)) => decoded,
+            _ => quote_or_hex(&self.value),
+        }
+    }
+}
+
+impl Filesystem {
+    /// Read the extended attributes of `inode`, sorted by name
+    pub fn xattrs(&self, inode: u64) -> Result<Vec<Xattr>> {
+        let mut xattrs = Vec::new();
+        for (_, data) in self.fs_tree_items(inode, BTRFS_XATTR_ITEM_KEY)? {
+            for entry in tree::parse_dir_items(&data)? {
+                xattrs.push(Xattr {
+                    name: OsStr::from_bytes(entry.name).to_os_string(),
+                    value: entry.data.to_vec(),
+                });
+            }
+        }
+        xattrs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(xattrs)
+    }
+}
+
+/// Decode a `posix_acl_xattr` value into the short text form `getfacl -c` and `setfacl` use,
+/// eg. `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`
+fn decode_acl(value: &[u8]) -> Result<String> {
+    let mut reader = Reader::new(value);
+    let version = reader.u32()?;
+    if version != POSIX_ACL_XATTR_VERSION {
+        bail!("unknown ACL version={}", version);
+    }
+    if !reader.remaining().is_multiple_of(8) {
+        bail!("ACL entries are truncated");
+    }
+
+    let mut entries = Vec::new();
+    while reader.remaining() > 0 {
+        let tag = reader.u16()?;
+        let perm = reader.u16()?;
+        let id = reader.u32()?;
+        let (kind, qualifier) = match tag {
+            ACL_USER_OBJ => ("user", String::new()),
+            ACL_USER => ("user", id.to_string()),
+            ACL_GROUP_OBJ => ("group", String::new()),
+            ACL_GROUP => ("group", id.to_string()),
+            ACL_MASK => ("mask", String::new()),
+            ACL_OTHER => ("other", String::new()),
+            _ => bail!("unknown ACL tag={:#x}", tag),
+        };
+        let perms: String = [(4, 'r'), (2, 'w'), (1, 'x')]
+            .iter()
+            .map(|(bit, c)| if perm & bit != 0 { *c } else { '-' })
+            .collect();
+        entries.push(format!("{}:{}:{}", kind, qualifier, perms));
+    }
+
+    Ok(entries.join(","))
+}
+
+/// Decode a `vfs_cap_data` value the way `getcap` prints it, eg. `cap_net_raw=ep`.
+/// Capabilities with the same flags are grouped. Namespaced capabilities end with the uid
+/// they belong to, eg. `[rootid=100000]`.
+fn decode_caps(value: &[u8]) -> Result<String> {
+    let mut reader = Reader::new(value);
+    let magic = reader.u32()?;
+    let revision = magic & VFS_CAP_REVISION_MASK;
+    let words = match revision {
+        VFS_CAP_REVISION_1 => 1,
+        VFS_CAP_REVISION_2 | VFS_CAP_REVISION_3 => 2,
+        _ => bail!("unknown capability revision={:#x}", revision),
+    };
+
+    let mut permitted = 0u64;
+    let mut inheritable = 0u64;
+    for word in 0..words {
+        permitted |= u64::from(reader.u32()?) << (32 * word);
+        inheritable |= u64::from(reader.u32()?) << (32 * word);
+    }
+    let effective = magic & VFS_CAP_FLAGS_EFFECTIVE != 0;
+
+    // (flags, capability names) in order of the lowest capability
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for bit in 0..64 {
+        let (p, i) = (permitted >> bit & 1 != 0, inheritable >> bit & 1 != 0);
+        if !p && !i {
+            continue;
+        }
+
+        let mut flags = String::new();
+        if effective {
+            flags.push('e');
+        }
+        if i {
+            flags.push('i');
+        }
+        if p {
+            flags.push('p');
+        }
+        let name = CAP_NAMES
+            .get(bit)
+            .map_or_else(|| format!("cap_{}", bit), |name| name.to_string());
+        match groups.iter_mut().find(|(f, _)| *f == flags) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((flags, vec![name])),
+        }
+    }
+
+    let mut out: Vec<String> = groups
+        .iter()
+        .map(|(flags, names)| format!("{}={}", names.join(","), flags))
+        .collect();
+    if revision == VFS_CAP_REVISION_3 {
+        out.push(format!("[rootid={}]", reader.u32()?));
+    }
+
+    Ok(out.join(" "))
+}
+
+/// Security labels are text, usually NUL terminated
+fn decode_label(value: &[u8]) -> Result<String> {
+    let value = value.strip_suffix(b"\0").unwrap_or(value);
+    match std::str::from_utf8(value) {
+        Ok(label) if !label.contains('\0') => Ok(label.to_string()),
+        _ => bail!("security label is not text"),
+    }
+}
+
+/// Quote `value` if it is text, with `"` and `\` escaped, else give it in hex, like `getfattr`
+fn quote_or_hex(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.chars().any(char::is_control) => {
+            format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+        _ => {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+#[cfg(test)]
+fn acl_entry(tag: u16, perm: u16, id: u32) -> Vec<u8> {
+    let mut entry = tag.to_le_bytes().to_vec();
+    entry.extend_from_slice(&perm.to_le_bytes());
+    entry.extend_from_slice(&id.to_le_bytes());
+    entry
+}
+
+#[test]
+fn test_decode_acl() {
+    let mut value = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    value.extend(acl_entry(ACL_USER_OBJ, 6, u32::MAX));
+    value.extend(acl_entry(ACL_USER, 4, 1000));
+    value.extend(acl_entry(ACL_GROUP_OBJ, 5, u32::MAX));
+    value.extend(acl_entry(ACL_MASK, 7, u32::MAX));
+    value.extend(acl_entry(ACL_OTHER, 0, u32::MAX));
+    assert_eq!(
+        decode_acl(&value).unwrap(),
+        "user::rw-,user:1000:r--,group::r-x,mask::rwx,other::---"
+    );
+
+    assert!(decode_acl(&value[..value.len() - 1]).is_err());
+    value[0] = 1;
+    assert!(decode_acl(&value).is_err());
+}
+
+#[test]
+fn test_decode_caps() {
+    // cap_net_bind_service and cap_net_raw permitted and effective, cap_chown inheritable
+    let mut value = (VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE)
+        .to_le_bytes()
+        .to_vec();
+    value.extend_from_slice(&(1u32 << 10 | 1 << 13).to_le_bytes());
+    value.extend_from_slice(&1u32.to_le_bytes());
+    value.extend_from_slice(&[0; 8]);
+    assert_eq!(
+        decode_caps(&value).unwrap(),
+        "cap_chown=ei cap_net_bind_service,cap_net_raw=ep"
+    );
+
+    // Revision 3 ends with the namespace root uid
+    value[3] = 3;
+    value.extend_from_slice(&100000u32.to_le_bytes());
+    assert_eq!(
+        decode_caps(&value).unwrap(),
+        "cap_chown=ei cap_net_bind_service,cap_net_raw=ep [rootid=100000]"
+    );
+
+    value[3] = 9;
+    assert!(decode_caps(&value).is_err());
+}
+
+#[test]
+fn test_display_value() {
+    let xattr = |name: &str, value: &[u8]| Xattr {
+        name: name.into(),
+        value: value.to_vec(),
+    };
+    assert_eq!(
+        xattr(XATTR_NAME_SELINUX, b"system_u:object_r:bin_t:s0\0").display_value(),
+        "system_u:object_r:bin_t:s0"
+    );
+    assert_eq!(
+        xattr("user.comment", b"say \"hi\"").display_value(),
+        "\"say \\\"hi\\\"\""
+    );
+    assert_eq!(xattr("user.raw", b"\x01\xff").display_value(), "0x01ff");
+    // Values that don't decode are shown raw
+    assert_eq!(xattr(XATTR_NAME_CAPS, b"\x01").display_value(), "0x01");
+}